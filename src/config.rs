@@ -36,6 +36,12 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 
+/// Initialize the default config without reading files.
+#[cfg(test)]
+pub fn init_default() {
+    CONFIG_INNER.get_or_init(|| toml::from_str("").expect("default config should parse"));
+}
+
 fn default_command_task_check() -> bool {
     true
 }
@@ -186,6 +192,8 @@ pub struct Config {
     #[serde(default = "default_clean")]
     pub clean: bool,
     #[serde(default)]
+    pub gather_facts: bool,
+    #[serde(default)]
     pub task: Task,
     #[serde(default)]
     pub error: Error,
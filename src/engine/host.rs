@@ -20,7 +20,7 @@ impl EngineHost {
     ) -> Result<()> {
         let src = src.as_ref();
         let dst = dst.as_ref();
-        run_cmd!(cp $src $dst)?;
+        run_cmd!(cp -r $src $dst)?;
 
         Ok(())
     }
@@ -33,7 +33,7 @@ impl EngineHost {
     ) -> Result<()> {
         let src = src.as_ref();
         let dst = dst.as_ref();
-        run_cmd!(cp $src $dst)?;
+        run_cmd!(cp -r $src $dst)?;
 
         Ok(())
     }
@@ -240,13 +240,14 @@ impl Engine {
 
         let check = params.check.unwrap_or(CONFIG.task.command.check);
 
-        if check && !out.success() {
+        let rc_matches = params.success_matches.as_ref().is_some_and(|m| m.has_rc());
+        if check && !out.success() && !rc_matches {
             let error = Error::CommandFailedExitCode(command_in_error.as_ref().to_string());
             return Self::run_wrap_error(error, None, params, &out);
         }
 
         if let Some(matches) = &params.failure_matches {
-            if matches.is_match(&stdout, &stderr)? {
+            if matches.is_match(&stdout, &stderr, out.rc())? {
                 out.matched = true;
                 if check {
                     let error =
@@ -257,7 +258,7 @@ impl Engine {
         }
 
         if let Some(matches) = &params.success_matches {
-            if !matches.is_match(&stdout, &stderr)? {
+            if !matches.is_match(&stdout, &stderr, out.rc())? {
                 if check {
                     let error =
                         Error::CommandFailedSuccsessMatches(command_in_error.as_ref().to_string());
@@ -5,12 +5,22 @@ use thiserror::Error as ThisError;
 
 #[derive(Debug, ThisError)]
 pub enum Error {
+    #[error(
+        "archive format of `{0}` is not supported, set `format` to `tar`, `tar-gz` or `tar-zst`"
+    )]
+    ArchiveFormatUnsupported(PathBuf),
+    #[error("{0}")]
+    AssertFailed(String),
+    #[error("backoff factor should be a finite number not less than 1, but get `{0}`")]
+    BadBackoffFactor(f64),
     #[error("extra var `{0}` does not have '=' to delimit name")]
     BadExtraVar(String),
     #[error("fs var name should be alphanumeric, but get `{0}`")]
     BadFsVar(String),
     #[error("failed to get init profile `{0}`")]
     BadInitProfile(String),
+    #[error("bad json-path `{0}`, expected `.path[0].key` with optional comparison")]
+    BadJsonPath(String),
     #[error("kind argument `{0}` does not have ':' to delimit name")]
     BadKindArg(String),
     #[error("kind argument `render` must be true or false, but get `{0}`")]
@@ -31,12 +41,24 @@ pub enum Error {
     CommandFailedFailureMatches(String),
     #[error("command `{0}` failed: don't match success matches")]
     CommandFailedSuccsessMatches(String),
+    #[error("continue task is run outside of a task with items")]
+    ContinueOutsideItems,
+    #[error("failed to read file `{0}` on the worker")]
+    EditFileRead(PathBuf),
     #[error("variables `{0}` are not set for taskline `{1}`")]
     EnsureAbsentVars(String, String),
     #[error("failed to setup incus volume `{0}`")]
     FailSetupIncusVolume(String),
+    #[error("destination `{0}` of file task content is a directory")]
+    FileDstDirectory(PathBuf),
+    #[error("file task option `{0}` could be used only {1}")]
+    FileOptionNotApplicable(String, String),
+    #[error("checksum mismatch for `{0}` fetched from the worker")]
+    GetChecksumMismatch(PathBuf),
     #[error("get task's src `{0}` has no filename")]
     GetSrcFilename(PathBuf),
+    #[error("no files on the worker match `{0}`")]
+    GetSrcNoMatch(PathBuf),
     #[error("trying to init manifest `{0}` that already exists")]
     InitManifestExists(PathBuf),
     #[error("required argument `{0}` is not set")]
@@ -53,20 +75,44 @@ pub enum Error {
     NoWorkersForTask(String),
     #[error("workers should be set")]
     NoWorkers,
+    #[error("command `{0}` with `on-worker` can be used only in tasks")]
+    OnWorkerNoWorker(String),
+    #[error("line `{0}` of kv command output does not have '=' to delimit key")]
+    ResultKvNoDelimiter(String),
+    #[error("failed to parse command output as {0}")]
+    ResultParse(String),
+    #[error("command output does not match result regex `{0}`")]
+    ResultRegexNoMatch(String),
+    #[error("cannot detect table format of file `{0}`, set `format`")]
+    TableFileFormat(PathBuf),
+    #[error("variable `tasks` conflicts with results of tasks required by `{0}`")]
+    TasksVarConflict(String),
+    #[error("template `{0}` has no filename")]
+    TemplateFilename(PathBuf),
     #[error("failed tsort in {0}")]
     TSort(String),
+    #[error("try until condition `{0}` is not met after {1} attempts")]
+    TryUntil(String, u32),
     #[error("unknown variable kind `{0}`")]
     UnknownVarKind(String),
     #[error("unknown variable type `{0}`")]
     UnknownVarType(String),
     #[error("special task `{0}` does not work on this engine")]
     UnsupportedSpecialTask(String),
+    #[error("try until `{0}` should be a bool, but it is `{1}`")]
+    UntilNotBool(String, String),
     #[error("cannot use tasklines `{0}` from the `{1}`")]
     UseTasklines(String, PathBuf),
     #[error("cannot use vars `{0}` from the `{1}`")]
     UseVars(String, PathBuf),
     #[error("{0}")]
     User(String, i32, bool),
+    #[error("timed out waiting for {0} after {1} seconds")]
+    WaitForTimeout(String, f64),
+    #[error("when `{0}` should be a bool, but it is `{1}`")]
+    WhenNotBool(String, String),
+    #[error("function `worker_cmd` can be used only in tasks")]
+    WorkerCmdNoWorker,
     #[error("failed to setup worker `{0}`")]
     WorkerSetupFailed(String),
     #[error("argument `{0}` has wrong type")]
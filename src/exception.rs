@@ -3,4 +3,6 @@ use serde_json::Value;
 #[derive(Clone, Debug)]
 pub enum Exception {
     BreakTaskline { taskline: Option<String>, result: Value },
+    Continue { result: Value },
+    Return { result: Value },
 }
@@ -0,0 +1,102 @@
+use serde_json::{json, Map, Value};
+
+const OS_RELEASE_MARKER: &str = "--- os-release";
+
+pub const PROBE: &str = r#"echo "arch=$(uname -m)"
+echo "kernel=$(uname -r)"
+echo "nproc=$(nproc 2>/dev/null || getconf _NPROCESSORS_ONLN 2>/dev/null)"
+echo "memory=$(awk '/^MemTotal:/ {print $2}' /proc/meminfo 2>/dev/null)"
+echo "hostname=$(hostname 2>/dev/null || cat /etc/hostname 2>/dev/null)"
+echo "default_ip=$(ip -4 route get 1.1.1.1 2>/dev/null | sed -n 's/.* src \([^ ]*\).*/\1/p')"
+for pm in apt-get dnf yum zypper pacman apk; do
+    if command -v $pm >/dev/null 2>&1; then echo "package_manager=$pm"; break; fi
+done
+if [ -d /run/systemd/system ]; then
+    echo "init=systemd"
+elif command -v openrc >/dev/null 2>&1; then
+    echo "init=openrc"
+else
+    echo "init=$(cat /proc/1/comm 2>/dev/null)"
+fi
+echo "--- os-release"
+cat /etc/os-release 2>/dev/null || cat /usr/lib/os-release 2>/dev/null
+true"#;
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(value) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return value;
+        }
+    }
+
+    value
+}
+
+fn non_empty(value: &str) -> Value {
+    if value.is_empty() {
+        Value::Null
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+pub fn parse(out: &str) -> Value {
+    let (probe, os_release) = out.split_once(OS_RELEASE_MARKER).unwrap_or((out, ""));
+
+    let mut facts = Map::new();
+    for line in probe.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = match key {
+                "nproc" => value.parse::<u64>().map(Value::from).unwrap_or(Value::Null),
+                "memory" => value.parse::<u64>().map(|kb| json!(kb / 1024)).unwrap_or(Value::Null),
+                _ => non_empty(value),
+            };
+            let key = if key == "memory" { "memory_mb" } else { key };
+            facts.insert(key.to_string(), value);
+        }
+    }
+    facts.entry("package_manager").or_insert(Value::Null);
+
+    let mut os = Map::new();
+    for line in os_release.lines() {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            os.insert(key.trim().to_lowercase(), Value::String(unquote(value).to_string()));
+        }
+    }
+    facts.insert("os".to_string(), Value::Object(os));
+
+    Value::Object(facts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_probe() {
+        let out = "arch=x86_64\nkernel=6.1.0\nnproc=4\nmemory=2048000\nhostname=box\n\
+                   default_ip=\npackage_manager=apt-get\ninit=systemd\n--- os-release\n\
+                   # comment\nID=debian\nVERSION_ID=\"12\"\nPRETTY_NAME='Debian 12'\n";
+        let facts = parse(out);
+        assert_eq!(facts["arch"], "x86_64");
+        assert_eq!(facts["nproc"], 4);
+        assert_eq!(facts["memory_mb"], 2000);
+        assert_eq!(facts["default_ip"], Value::Null);
+        assert_eq!(facts["package_manager"], "apt-get");
+        assert_eq!(facts["os"]["id"], "debian");
+        assert_eq!(facts["os"]["version_id"], "12");
+        assert_eq!(facts["os"]["pretty_name"], "Debian 12");
+    }
+
+    #[test]
+    fn parse_empty() {
+        let facts = parse("");
+        assert_eq!(facts["package_manager"], Value::Null);
+        assert_eq!(facts["os"], json!({}));
+    }
+}
@@ -1,13 +1,16 @@
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use anyhow::Context as AnyhowContext;
 use anyhow::Result;
 use file_lock::{FileLock, FileOptions};
 use rust_embed::RustEmbed;
 
 use crate::config::{config_dir, CONFIG};
 
+// configs are read through `AssetAllFiles`
+#[allow(dead_code)]
 #[derive(RustEmbed)]
 #[folder = "files/configs"]
 #[prefix = "configs/"]
@@ -93,3 +96,52 @@ pub fn install_all() -> Result<()> {
 
     Ok(())
 }
+
+/// Recursively list regular files in the directory, sorted by path.
+pub fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)
+            .with_context(|| format!("Failed to read directory `{}`", current.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tmpdir::mktemp_dir;
+
+    #[test]
+    fn walk_files_recursive_sorted() -> Result<()> {
+        let dir = mktemp_dir()?;
+        fs::create_dir_all(dir.join("b/c"))?;
+        fs::create_dir(dir.join("empty"))?;
+        for file in ["z", "a", "b/c/d", "b/e"] {
+            fs::write(dir.join(file), file)?;
+        }
+
+        let files = walk_files(&dir)?;
+        let names = files
+            .iter()
+            .map(|f| f.strip_prefix(&dir).unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b/c/d", "b/e", "z"]);
+
+        assert!(walk_files(&dir.join("absent")).is_err());
+
+        Ok(())
+    }
+}
@@ -1,13 +1,19 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
 use anyhow::Context as AnyhowContext;
-use anyhow::{bail, Result};
+use anyhow::Result;
 use cmd_lib::run_fun;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 use crate::error::Error;
 use crate::render::Render;
 use crate::string_or_int::StringOrInt;
+use crate::task_type::CmdParams;
 use crate::template::Context;
+use crate::worker::Worker;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -60,6 +66,48 @@ pub struct ItemsSeq {
 pub struct ItemsCommand {
     #[serde(alias = "cmd")]
     pub command: String,
+    #[serde(default)]
+    pub on_worker: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ItemsFile {
+    pub file: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ItemsGlob {
+    pub glob: String,
+}
+
+pub fn command_out(command: &str, on_worker: bool, worker: Option<&Worker>) -> Result<String> {
+    if !on_worker {
+        return Ok(run_fun!(sh -c $command)?);
+    }
+    let worker = worker.ok_or_else(|| Error::OnWorkerNoWorker(command.to_string()))?;
+
+    Ok(worker.shell(command, &CmdParams::quiet())?.stdout())
+}
+
+/// Paths matching the pattern, relative to the directory for relative patterns.
+pub fn glob_paths(dir: &Path, pattern: &str) -> Result<Vec<String>> {
+    let mut paths = vec![];
+    for path in glob::glob(&dir.join(pattern).to_string_lossy())? {
+        let path = path?;
+        let path = path.strip_prefix(dir).unwrap_or(&path);
+        paths.push(path.to_string_lossy().to_string());
+    }
+
+    Ok(paths)
+}
+
+pub fn manifest_path(context: &Context, path: &str) -> PathBuf {
+    match context.get("manifest_dir").and_then(|d| d.as_str()) {
+        Some(dir) => Path::new(dir).join(path),
+        None => PathBuf::from(path),
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -75,6 +123,113 @@ pub struct ItemsJson {
     pub json: String,
 }
 
+type ItemsRow = IndexMap<String, Value>;
+
+fn render_row(row: &ItemsRow, context: &Context, place: &str) -> Result<Map<String, Value>> {
+    let mut combination = Map::new();
+    for (name, value) in row {
+        let value = match value {
+            Value::String(s) => Value::String(s.render(context, format!("{name} in {place}"))?),
+            value => value.to_owned(),
+        };
+        combination.insert(name.to_string(), value);
+    }
+
+    Ok(combination)
+}
+
+/// Values of a matrix or zip field, arrays keep types of their elements.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ItemsValues {
+    Values(Vec<Value>),
+    Items(Items),
+}
+
+impl ItemsValues {
+    fn values(&self, context: &Context, worker: Option<&Worker>) -> Result<Vec<Value>> {
+        match self {
+            ItemsValues::Values(values) => Ok(values.to_owned()),
+            ItemsValues::Items(items) => items.values(context, worker),
+        }
+    }
+}
+
+fn row_matches(combination: &Map<String, Value>, row: &Map<String, Value>) -> bool {
+    row.iter().all(|(name, value)| combination.get(name) == Some(value))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ItemsMatrix {
+    pub matrix: IndexMap<String, ItemsValues>,
+    #[serde(default)]
+    pub exclude: Vec<ItemsRow>,
+    #[serde(default)]
+    pub include: Vec<ItemsRow>,
+}
+
+impl ItemsMatrix {
+    fn values(&self, context: &Context, worker: Option<&Worker>) -> Result<Vec<Value>> {
+        let mut combinations = vec![Map::new()];
+        for (name, items) in &self.matrix {
+            let values = items.values(context, worker)?;
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.to_owned();
+                        combination.insert(name.to_string(), value.to_owned());
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        let mut excludes = Vec::with_capacity(self.exclude.len());
+        for row in &self.exclude {
+            excludes.push(render_row(row, context, "items matrix exclude")?);
+        }
+        combinations.retain(|c| !excludes.iter().any(|row| row_matches(c, row)));
+
+        for row in &self.include {
+            let row = render_row(row, context, "items matrix include")?;
+            if !combinations.contains(&row) {
+                combinations.push(row);
+            }
+        }
+
+        Ok(combinations.into_iter().map(Value::Object).collect())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ItemsZip {
+    pub zip: IndexMap<String, ItemsValues>,
+}
+
+impl ItemsZip {
+    fn values(&self, context: &Context, worker: Option<&Worker>) -> Result<Vec<Value>> {
+        let mut lists = Vec::with_capacity(self.zip.len());
+        for (name, items) in &self.zip {
+            lists.push((name, items.values(context, worker)?));
+        }
+        let len = lists.iter().map(|(_, list)| list.len()).min().unwrap_or_default();
+
+        let mut combinations = Vec::with_capacity(len);
+        for i in 0..len {
+            let mut combination = Map::new();
+            for (name, list) in &lists {
+                combination.insert(name.to_string(), list[i].to_owned());
+            }
+            combinations.push(Value::Object(combination));
+        }
+
+        Ok(combinations)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(untagged)]
@@ -84,10 +239,94 @@ pub enum Items {
     Command(ItemsCommand),
     Json(ItemsJson),
     Variable(ItemsVariable),
+    File(ItemsFile),
+    Glob(ItemsGlob),
+    Matrix(ItemsMatrix),
+    Zip(ItemsZip),
 }
 
 impl Items {
-    pub fn list(&self, context: &Context) -> Result<Vec<String>> {
+    pub fn key(item: &Value) -> String {
+        match item {
+            Value::String(s) => s.to_string(),
+            Value::Object(o) => o
+                .values()
+                .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or_else(|| v.to_string()))
+                .collect::<Vec<_>>()
+                .join("-"),
+            v => v.to_string(),
+        }
+    }
+
+    /// Key of the item, fields of matrix and zip items are joined in the
+    /// declaration order.
+    pub fn item_key(&self, item: &Value) -> String {
+        let names: Vec<&String> = match self {
+            Items::Matrix(matrix) => matrix.matrix.keys().collect(),
+            Items::Zip(zip) => zip.zip.keys().collect(),
+            _ => return Items::key(item),
+        };
+        let Value::Object(o) = item else {
+            return Items::key(item);
+        };
+        let rest = o.iter().filter(|(name, _)| !names.contains(name)).map(|(_, value)| value);
+        names
+            .iter()
+            .filter_map(|name| o.get(*name))
+            .chain(rest)
+            .map(Items::key)
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub fn insert(&self, context: &mut Context, var: &str, item: &Value) {
+        context.insert(var, item);
+        if let (Items::Matrix(_) | Items::Zip(_), Value::Object(o)) = (self, item) {
+            for (name, value) in o {
+                context.insert(name, value);
+            }
+        }
+    }
+
+    fn json_values(value: Value) -> Option<Vec<Value>> {
+        match value {
+            Value::Array(a) => Some(
+                a.into_iter()
+                    .map(|item| match item {
+                        Value::Bool(b) => Value::String(b.to_string()),
+                        Value::Null => Value::String("".to_string()),
+                        Value::Number(n) => Value::String(n.to_string()),
+                        item => item,
+                    })
+                    .collect(),
+            ),
+            Value::Object(o) => Some(o.into_iter().map(|(k, _)| Value::String(k)).collect()),
+            _ => None,
+        }
+    }
+
+    pub fn values(&self, context: &Context, worker: Option<&Worker>) -> Result<Vec<Value>> {
+        match self {
+            Items::Matrix(matrix) => matrix.values(context, worker),
+            Items::Zip(zip) => zip.values(context, worker),
+            Items::Json(json) => {
+                let json_str = json.json.render(context, "list items json")?;
+                let json = serde_json::from_str(&json_str)?;
+                Items::json_values(json).ok_or_else(|| Error::WrongItemsJsonType(json_str).into())
+            }
+            Items::Variable(variable) => {
+                let var_name = variable.variable.render(context, "list items variable")?;
+                let var = context
+                    .get(&var_name)
+                    .ok_or_else(|| Error::NoItemsVar(var_name.to_string()))?;
+                Items::json_values(var.to_owned())
+                    .ok_or_else(|| Error::WrongItemsVarType(var_name).into())
+            }
+            _ => Ok(self.list(context, worker)?.into_iter().map(Value::String).collect()),
+        }
+    }
+
+    pub fn list(&self, context: &Context, worker: Option<&Worker>) -> Result<Vec<String>> {
         let items = match self {
             Items::Words(words) => {
                 words.iter().map(|w| w.to_string()).collect::<Vec<_>>().to_owned()
@@ -100,57 +339,129 @@ impl Items {
             }
             Items::Command(command) => {
                 let cmd = command.command.render(context, "list items command")?;
-                let out = run_fun!(sh -c $cmd)?;
+                let out = command_out(&cmd, command.on_worker, worker)?;
                 out.lines().map(|l| l.to_string()).collect::<Vec<String>>()
             }
-            Items::Json(json) => {
-                let json_str = json.json.render(context, "list items json")?;
-                let json = serde_json::from_str(&json_str)?;
-                match json {
-                    Value::Array(a) => {
-                        let mut items = Vec::with_capacity(a.len());
-                        for item in a {
-                            let item = match item {
-                                Value::Bool(b) => b.to_string(),
-                                Value::Null => "".to_string(),
-                                Value::Number(n) => n.to_string(),
-                                Value::String(s) => s.to_string(),
-                                _ => bail!(Error::WrongItemsJsonType(json_str)),
-                            };
-                            items.push(item);
-                        }
-                        items
-                    }
-                    Value::Object(o) => o.keys().map(|k| k.to_string()).collect(),
-                    _ => bail!(Error::WrongItemsJsonType(json_str)),
-                }
+            Items::File(file) => {
+                let path = manifest_path(context, &file.file.render(context, "list items file")?);
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read items file `{}`", path.display()))?;
+                content
+                    .lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| l.to_string())
+                    .collect()
             }
-            Items::Variable(variable) => {
-                let var_name = variable.variable.render(context, "list items variable")?;
-                let var = context
-                    .get(&var_name)
-                    .ok_or_else(|| Error::NoItemsVar(var_name.to_string()))?;
-                match var {
-                    Value::Array(a) => {
-                        let mut items = Vec::with_capacity(a.len());
-                        for item in a {
-                            let item = match item {
-                                Value::Bool(b) => b.to_string(),
-                                Value::Null => "".to_string(),
-                                Value::Number(n) => n.to_string(),
-                                Value::String(s) => s.to_string(),
-                                _ => bail!(Error::WrongItemsVarType(var_name)),
-                            };
-                            items.push(item);
-                        }
-                        items
-                    }
-                    Value::Object(o) => o.keys().map(|k| k.to_string()).collect(),
-                    _ => bail!(Error::WrongItemsVarType(var_name)),
-                }
+            Items::Glob(glob) => {
+                let pattern = glob.glob.render(context, "list items glob")?;
+                glob_paths(&manifest_path(context, ""), &pattern)?
+            }
+            Items::Json(_) | Items::Variable(_) | Items::Matrix(_) | Items::Zip(_) => {
+                self.values(context, worker)?.iter().map(|v| self.item_key(v)).collect::<Vec<_>>()
             }
         };
 
         Ok(items)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_exclude_include() -> Result<()> {
+        let items = r#"
+            matrix = { distro = ["alt", "deb"], arch = ["x86_64", "aarch64"] }
+            exclude = [{ distro = "deb", arch = "aarch64" }]
+            include = [{ distro = "arch", arch = "x86_64" }, { distro = "alt", arch = "x86_64" }]
+        "#;
+        let items = toml::from_str::<Items>(items)?;
+        let list = items.list(&Context::new(), None)?;
+        assert_eq!(list, ["alt-x86_64", "alt-aarch64", "deb-x86_64", "arch-x86_64"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn matrix_typed_values() -> Result<()> {
+        let items = r#"
+            matrix = { debug = [true, false], level = [1, 2] }
+            exclude = [{ debug = false, level = 2 }]
+        "#;
+        let items = toml::from_str::<Items>(items)?;
+        let values = items.values(&Context::new(), None)?;
+        assert_eq!(
+            values,
+            [
+                serde_json::json!({"debug": true, "level": 1}),
+                serde_json::json!({"debug": true, "level": 2}),
+                serde_json::json!({"debug": false, "level": 1}),
+            ]
+        );
+        assert_eq!(items.list(&Context::new(), None)?, ["true-1", "true-2", "false-1"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_on_worker() -> Result<()> {
+        let worker = Worker::test_host();
+        assert_eq!(command_out("echo host", false, None)?, "host");
+        assert_eq!(command_out("echo worker", true, Some(&worker))?, "worker\n");
+
+        let err = command_out("echo worker", true, None).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::OnWorkerNoWorker(_))));
+
+        let items = r#"
+            command = "printf 'a\nb\n'"
+            on-worker = true
+        "#;
+        let items = toml::from_str::<Items>(items)?;
+        assert_eq!(items.list(&Context::new(), Some(&worker))?, ["a", "b"]);
+        assert!(items.list(&Context::new(), None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_objects() -> Result<()> {
+        let items = toml::from_str::<Items>(r#"json = '[{"name": "a", "port": 1}, 2, null]'"#)?;
+        let values = items.values(&Context::new(), None)?;
+        assert_eq!(values, [serde_json::json!({"name": "a", "port": 1}), "2".into(), "".into()]);
+        assert_eq!(items.list(&Context::new(), None)?, ["a-1", "2", ""]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_and_glob() -> Result<()> {
+        let dir = crate::tmpdir::mktemp_dir()?;
+        fs::write(dir.join("hosts"), "# hosts\na\n\n  b \n")?;
+        fs::create_dir(dir.join("roles"))?;
+        fs::write(dir.join("roles/web.toml"), "")?;
+        fs::write(dir.join("roles/db.toml"), "")?;
+        let mut context = Context::new();
+        context.insert("manifest_dir", &dir.to_string_lossy().to_string());
+
+        let items = toml::from_str::<Items>(r#"file = "hosts""#)?;
+        assert_eq!(items.list(&context, None)?, ["a", "b"]);
+        let items = toml::from_str::<Items>(r#"glob = "roles/*.toml""#)?;
+        assert_eq!(items.list(&context, None)?, ["roles/db.toml", "roles/web.toml"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn zip_shortest() -> Result<()> {
+        let items = toml::from_str::<Items>(r#"zip = { a = [1, 2, 3], b = ["x", "y"] }"#)?;
+        let values = items.values(&Context::new(), None)?;
+        assert_eq!(
+            values,
+            [serde_json::json!({"a": 1, "b": "x"}), serde_json::json!({"a": 2, "b": "y"})]
+        );
+
+        Ok(())
+    }
+}
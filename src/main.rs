@@ -24,6 +24,7 @@ mod config;
 mod engine;
 mod error;
 mod exception;
+mod facts;
 mod files;
 mod fs_var;
 mod init;
@@ -30,6 +30,8 @@ pub struct DefaultWorker {
     pub table_by_item: Option<Table>,
     pub table_by_name: Option<Table>,
     pub engine: Option<Engine>,
+    #[serde(rename = "gather-facts")]
+    pub gather_facts: Option<bool>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -388,6 +390,7 @@ pub struct Worker {
     #[serde(default)]
     pub table_by_name: Table,
     pub engine: Option<Engine>,
+    pub gather_facts: Option<bool>,
 }
 
 fn default_taskset_elem_workers() -> Vec<String> {
@@ -420,12 +423,15 @@ fn default_taskset() -> Taskset {
     let task = Task {
         table: None,
         condition: None,
+        when: None,
+        if_cmd: None,
         items_table: None,
         clean_vars: Default::default(),
         parallel: true,
         result_fs_var: None,
         vars: Default::default(),
         export_vars: Default::default(),
+        register: None,
         task_type,
         try_: None,
     };
@@ -1,26 +1,205 @@
-use anyhow::Result;
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
+use crate::error::Error;
 use crate::render::Render;
 use crate::template::Context;
 
+#[derive(Clone, Debug)]
+pub struct MatchRe {
+    source: String,
+    re: Option<Regex>,
+}
+
+impl MatchRe {
+    fn compile(source: &str, anchored: bool) -> Result<Regex> {
+        let re =
+            if anchored { Regex::new(&format!("^(?:{source})$"))? } else { Regex::new(source)? };
+
+        Ok(re)
+    }
+
+    fn regex(&self, anchored: bool) -> Result<Regex> {
+        match &self.re {
+            Some(re) => Ok(re.to_owned()),
+            None => Self::compile(&self.source, anchored),
+        }
+    }
+
+    fn render(&self, context: &Context, place: String, anchored: bool) -> Result<Self> {
+        let source = self.source.render(context, place)?;
+        let re = Some(Self::compile(&source, anchored)?);
+
+        Ok(Self { source, re })
+    }
+}
+
+impl From<&str> for MatchRe {
+    fn from(source: &str) -> Self {
+        Self { source: source.to_string(), re: None }
+    }
+}
+
+impl Serialize for MatchRe {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MatchRe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self { source: String::deserialize(deserializer)?, re: None })
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinesCount {
+    eq: Option<usize>,
+    ne: Option<usize>,
+    lt: Option<usize>,
+    le: Option<usize>,
+    gt: Option<usize>,
+    ge: Option<usize>,
+}
+
+impl LinesCount {
+    fn is_match(&self, count: usize) -> bool {
+        self.eq.iter().all(|&n| count == n)
+            && self.ne.iter().all(|&n| count != n)
+            && self.lt.iter().all(|&n| count < n)
+            && self.le.iter().all(|&n| count <= n)
+            && self.gt.iter().all(|&n| count > n)
+            && self.ge.iter().all(|&n| count >= n)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum JsonSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, PartialEq)]
+struct JsonQuery {
+    path: Vec<JsonSegment>,
+    cmp: Option<(String, Value)>,
+}
+
+impl JsonQuery {
+    const OPS: [&'static str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+    fn parse(query: &str) -> Result<Self> {
+        let bad = || Error::BadJsonPath(query.to_string());
+        let query = query.trim();
+        let end = query.find(|c: char| c.is_whitespace() || "=!<>".contains(c));
+        let (path_str, rest) = query.split_at(end.unwrap_or(query.len()));
+        let Some(mut path_str) = path_str.strip_prefix('.') else {
+            bail!(bad());
+        };
+
+        let mut path = vec![];
+        while !path_str.is_empty() {
+            if let Some(rest) = path_str.strip_prefix('[') {
+                let (index, rest) = rest.split_once(']').ok_or_else(bad)?;
+                path.push(JsonSegment::Index(index.parse().map_err(|_| bad())?));
+                path_str = rest.strip_prefix('.').unwrap_or(rest);
+            } else {
+                let end = path_str.find(['.', '[']).unwrap_or(path_str.len());
+                let (key, rest) = path_str.split_at(end);
+                path.push(JsonSegment::Key(key.to_string()));
+                path_str = rest.strip_prefix('.').unwrap_or(rest);
+            }
+        }
+
+        let rest = rest.trim();
+        let cmp = if rest.is_empty() {
+            None
+        } else {
+            let op = Self::OPS.iter().find(|op| rest.starts_with(*op)).ok_or_else(bad)?;
+            let value = serde_json::from_str(rest[op.len()..].trim()).map_err(|_| bad())?;
+            Some((op.to_string(), value))
+        };
+
+        Ok(Self { path, cmp })
+    }
+
+    fn is_match(&self, json: &Value) -> bool {
+        let mut value = json;
+        for segment in &self.path {
+            let next = match segment {
+                JsonSegment::Key(key) => value.get(key),
+                JsonSegment::Index(index) => value.get(index),
+            };
+            match next {
+                Some(next) => value = next,
+                None => return false,
+            }
+        }
+
+        let Some((op, expected)) = &self.cmp else {
+            return !matches!(value, Value::Null | Value::Bool(false));
+        };
+        let ordering = match (value, expected) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (a, b) if a == b => Some(Ordering::Equal),
+            _ => None,
+        };
+        match op.as_str() {
+            "==" => ordering == Some(Ordering::Equal),
+            "!=" => ordering != Some(Ordering::Equal),
+            "<" => ordering == Some(Ordering::Less),
+            "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            ">" => ordering == Some(Ordering::Greater),
+            ">=" => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Matches {
     And(Vec<Matches>),
     Or(Vec<Matches>),
-    AnyRe(String),
-    ErrRe(String),
-    OutRe(String),
+    Not(Box<Matches>),
+    AnyRe(MatchRe),
+    ErrRe(MatchRe),
+    OutRe(MatchRe),
+    AllLines(MatchRe),
+    AnyLine(MatchRe),
+    OutContains(String),
+    LinesCount(LinesCount),
+    JsonPath(String),
+    Rc(Vec<i32>),
 }
 
 impl Matches {
-    pub fn is_match<O: AsRef<str>, E: AsRef<str>>(&self, out: O, err: E) -> Result<bool> {
+    pub fn has_rc(&self) -> bool {
+        match self {
+            Matches::And(ms) | Matches::Or(ms) => ms.iter().any(|m| m.has_rc()),
+            Matches::Not(m) => m.has_rc(),
+            Matches::Rc(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_match<O: AsRef<str>, E: AsRef<str>>(
+        &self,
+        out: O,
+        err: E,
+        rc: Option<i32>,
+    ) -> Result<bool> {
+        let (out, err) = (out.as_ref(), err.as_ref());
         match self {
             Matches::And(ms) => {
                 for m in ms {
-                    if !m.is_match(out.as_ref(), err.as_ref())? {
+                    if !m.is_match(out, err, rc)? {
                         return Ok(false);
                     }
                 }
@@ -29,37 +208,48 @@ impl Matches {
             }
             Matches::Or(ms) => {
                 for m in ms {
-                    if m.is_match(out.as_ref(), err.as_ref())? {
+                    if m.is_match(out, err, rc)? {
                         return Ok(true);
                     }
                 }
 
                 Ok(false)
             }
+            Matches::Not(m) => Ok(!m.is_match(out, err, rc)?),
             Matches::AnyRe(re) => {
-                let re = Regex::new(re)?;
-                Ok(re.is_match(out.as_ref()) || re.is_match(err.as_ref()))
+                let re = re.regex(false)?;
+                Ok(re.is_match(out) || re.is_match(err))
             }
-            Matches::ErrRe(re) => {
-                let re = Regex::new(re)?;
-                Ok(re.is_match(err.as_ref()))
+            Matches::ErrRe(re) => Ok(re.regex(false)?.is_match(err)),
+            Matches::OutRe(re) => Ok(re.regex(false)?.is_match(out)),
+            Matches::AllLines(re) => {
+                let re = re.regex(true)?;
+                Ok(out.lines().all(|l| re.is_match(l)))
             }
-            Matches::OutRe(re) => {
-                let re = Regex::new(re)?;
-                Ok(re.is_match(out.as_ref()))
+            Matches::AnyLine(re) => {
+                let re = re.regex(true)?;
+                Ok(out.lines().any(|l| re.is_match(l)))
             }
+            Matches::OutContains(s) => Ok(out.contains(s.as_str())),
+            Matches::LinesCount(count) => Ok(count.is_match(out.lines().count())),
+            Matches::JsonPath(query) => {
+                let query = JsonQuery::parse(query)?;
+                Ok(serde_json::from_str(out).is_ok_and(|json| query.is_match(&json)))
+            }
+            Matches::Rc(codes) => Ok(rc.is_some_and(|rc| codes.contains(&rc))),
         }
     }
 }
 
 impl Render for Matches {
     fn render<S: AsRef<str>>(&self, context: &Context, place: S) -> Result<Self> {
+        let place = place.as_ref();
         match self {
             Matches::And(ms) => {
                 let mut new_ms: Vec<Matches> = Vec::with_capacity(ms.len());
 
                 for m in ms {
-                    new_ms.push(m.render(context, place.as_ref())?);
+                    new_ms.push(m.render(context, place)?);
                 }
 
                 Ok(Matches::And(new_ms))
@@ -68,20 +258,36 @@ impl Render for Matches {
                 let mut new_ms: Vec<Matches> = Vec::with_capacity(ms.len());
 
                 for m in ms {
-                    new_ms.push(m.render(context, place.as_ref())?);
+                    new_ms.push(m.render(context, place)?);
                 }
 
                 Ok(Matches::Or(new_ms))
             }
+            Matches::Not(m) => Ok(Matches::Not(Box::new(m.render(context, place)?))),
             Matches::AnyRe(re) => {
-                Ok(Matches::AnyRe(re.render(context, format!("any-re in {}", place.as_ref()))?))
+                Ok(Matches::AnyRe(re.render(context, format!("any-re in {place}"), false)?))
             }
             Matches::ErrRe(re) => {
-                Ok(Matches::ErrRe(re.render(context, format!("err-re in {}", place.as_ref()))?))
+                Ok(Matches::ErrRe(re.render(context, format!("err-re in {place}"), false)?))
             }
             Matches::OutRe(re) => {
-                Ok(Matches::OutRe(re.render(context, format!("out-re in {}", place.as_ref()))?))
+                Ok(Matches::OutRe(re.render(context, format!("out-re in {place}"), false)?))
+            }
+            Matches::AllLines(re) => {
+                Ok(Matches::AllLines(re.render(context, format!("all-lines in {place}"), true)?))
+            }
+            Matches::AnyLine(re) => {
+                Ok(Matches::AnyLine(re.render(context, format!("any-line in {place}"), true)?))
+            }
+            Matches::OutContains(s) => {
+                Ok(Matches::OutContains(s.render(context, format!("out-contains in {place}"))?))
             }
+            Matches::JsonPath(query) => {
+                let query = query.render(context, format!("json-path in {place}"))?;
+                JsonQuery::parse(&query)?;
+                Ok(Matches::JsonPath(query))
+            }
+            Matches::LinesCount(_) | Matches::Rc(_) => Ok(self.to_owned()),
         }
     }
 }
@@ -92,28 +298,28 @@ mod tests {
 
     #[test]
     fn simple_out() -> Result<()> {
-        assert!(Matches::OutRe("version".to_string()).is_match("version", "").unwrap());
+        assert!(Matches::OutRe("version".into()).is_match("version", "", None).unwrap());
 
         Ok(())
     }
 
     #[test]
     fn simple_err() -> Result<()> {
-        assert!(Matches::ErrRe("version".to_string()).is_match("", "version").unwrap());
+        assert!(Matches::ErrRe("version".into()).is_match("", "version", None).unwrap());
 
         Ok(())
     }
 
     #[test]
     fn simple_any_out() -> Result<()> {
-        assert!(Matches::AnyRe("version".to_string()).is_match("version", "").unwrap());
+        assert!(Matches::AnyRe("version".into()).is_match("version", "", None).unwrap());
 
         Ok(())
     }
 
     #[test]
     fn simple_any_err() -> Result<()> {
-        assert!(Matches::AnyRe("version".to_string()).is_match("", "version")?);
+        assert!(Matches::AnyRe("version".into()).is_match("", "version", None)?);
 
         Ok(())
     }
@@ -122,8 +328,8 @@ mod tests {
     fn simple_or() -> Result<()> {
         let matches = "or = [ { err-re = 'LLM' }, { err-re = 'toml' }]";
         let matches = toml::from_str::<Matches>(matches)?;
-        assert!(matches.is_match("", "toml")?);
-        assert!(matches.is_match("", "LLM")?);
+        assert!(matches.is_match("", "toml", None)?);
+        assert!(matches.is_match("", "LLM", None)?);
 
         Ok(())
     }
@@ -132,9 +338,9 @@ mod tests {
     fn simple_and() -> Result<()> {
         let matches = "and = [ { err-re = 'LLM' }, { err-re = 'toml' }]";
         let matches = toml::from_str::<Matches>(matches)?;
-        assert!(!matches.is_match("", "toml")?);
-        assert!(!matches.is_match("", "LLM")?);
-        assert!(matches.is_match("", "toml LLM")?);
+        assert!(!matches.is_match("", "toml", None)?);
+        assert!(!matches.is_match("", "LLM", None)?);
+        assert!(matches.is_match("", "toml LLM", None)?);
 
         Ok(())
     }
@@ -144,10 +350,58 @@ mod tests {
         let matches =
             "and = [ { out-re = 'ls' }, {or = [{ err-re = 'LLM' }, { err-re = 'toml' }]}]";
         let matches = toml::from_str::<Matches>(matches)?;
-        assert!(matches.is_match("ls", "toml")?);
-        assert!(matches.is_match("ls", "LLM")?);
-        assert!(!matches.is_match("", "toml LLM")?);
-        assert!(!matches.is_match("ls", "")?);
+        assert!(matches.is_match("ls", "toml", None)?);
+        assert!(matches.is_match("ls", "LLM", None)?);
+        assert!(!matches.is_match("", "toml LLM", None)?);
+        assert!(!matches.is_match("ls", "", None)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn not_rc_and_contains() -> Result<()> {
+        let matches = "and = [{ rc = [0, 3] }, { not = { out-contains = 'a.b' } }]";
+        let matches = toml::from_str::<Matches>(matches)?;
+        assert!(matches.has_rc());
+        assert!(matches.is_match("axb", "", Some(3))?);
+        assert!(!matches.is_match("a.b", "", Some(0))?);
+        assert!(!matches.is_match("", "", Some(1))?);
+        assert!(!matches.is_match("", "", None)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lines() -> Result<()> {
+        let out = "ok 1\nok 2\n";
+        let count = toml::from_str::<Matches>("lines-count = { ge = 2, lt = 3 }")?;
+        assert!(count.is_match(out, "", None)?);
+        let all = toml::from_str::<Matches>("all-lines = 'ok \\d'")?;
+        assert!(all.is_match(out, "", None)?);
+        assert!(!all.is_match("ok 1\nnot ok 2", "", None)?);
+        let any = toml::from_str::<Matches>("any-line = 'ok'")?.render(&Context::new(), "test")?;
+        assert!(!any.is_match(out, "", None)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_path() -> Result<()> {
+        let out = r#"{"status": "ok", "items": [{"n": 3}], "down": false}"#;
+        for (query, expected) in [
+            (".status == \"ok\"", true),
+            (".status != \"ok\"", false),
+            (".items[0].n >= 3", true),
+            (".items[0].n < 3", false),
+            (".items[1]", false),
+            (".down", false),
+            (".", true),
+        ] {
+            let matches = Matches::JsonPath(query.to_string());
+            assert_eq!(matches.is_match(out, "", None)?, expected, "{query}");
+        }
+        assert!(!Matches::JsonPath(".status".to_string()).is_match("not json", "", None)?);
+        assert!(Matches::JsonPath("status".to_string()).render(&Context::new(), "test").is_err());
 
         Ok(())
     }
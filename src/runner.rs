@@ -3,13 +3,14 @@ use std::env;
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use anyhow::Context as AnyhowContext;
 use anyhow::{bail, Result};
 use log::warn;
 use rayon::prelude::*;
 use regex::RegexSet;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use crate::engine::ExistsAction;
 use crate::error::Error;
@@ -26,6 +27,34 @@ use crate::use_unit::UseUnit;
 use crate::vars::Vars;
 use crate::worker::Worker;
 
+fn tasks_context(results: &BTreeMap<String, BTreeMap<String, Value>>, worker: &str) -> Value {
+    let mut tasks = serde_json::Map::new();
+    for (name, workers) in results {
+        let own = workers.get(worker).cloned().unwrap_or(json!({ "result": null, "vars": {} }));
+        let mut task = own.as_object().cloned().unwrap_or_default();
+        task.insert("workers".to_string(), json!(workers));
+        tasks.insert(name.to_string(), Value::Object(task));
+    }
+
+    Value::Object(tasks)
+}
+
+/// Set the `tasks` variable for a taskset task with `requires`, a variable with
+/// the same name defined by the manifest is an error.
+fn insert_tasks_context(
+    context: &mut Context,
+    task: &str,
+    results: &BTreeMap<String, BTreeMap<String, Value>>,
+    worker: &str,
+) -> Result<()> {
+    if context.contains_key("tasks") {
+        bail!(Error::TasksVarConflict(task.to_string()));
+    }
+    context.insert("tasks", &tasks_context(results, worker));
+
+    Ok(())
+}
+
 fn save_layers(layers: &Vec<Vec<String>>) -> Result<()> {
     if let Ok(layers_file) = env::var("LINEUP_LAYERS") {
         let context = format!("save layers to `{}`", layers_file);
@@ -261,6 +290,7 @@ impl Runner {
 
         let layers = tsort(&tasks_graph, "taskset requires")?;
         save_layers(&layers)?;
+        let tasks_results = Mutex::new(BTreeMap::<String, BTreeMap<String, Value>>::new());
 
         for layer in layers {
             let mut workers_by_task = BTreeMap::new();
@@ -311,12 +341,20 @@ impl Runner {
                     .map(|w| w.to_owned())
                     .collect::<Vec<_>>();
                 let task = &taskset_elem.task;
+                let required_results = tasks_results
+                    .lock()
+                    .expect("taskset results lock is poisoned")
+                    .iter()
+                    .filter(|(name, _)| taskset_elem.requires.contains(*name))
+                    .map(|(name, results)| (name.to_owned(), results.to_owned()))
+                    .collect::<BTreeMap<_, _>>();
 
                 let env = Env {
                     dir: &self.dir,
                     storages: &self.storages,
                     tasklines: &self.tasklines,
                     workers: &provide_workers,
+                    task_workers: workers_by_task.get(name).map(|w| w.len()).unwrap_or_default(),
                 };
 
                 self.workers.par_iter().try_for_each(|worker| -> Result<()> {
@@ -328,12 +366,30 @@ impl Runner {
                     {
                         let mut context = context.to_owned();
                         context.insert("worker", &worker.name());
-                        let result =
-                            task.run(&Some(name), &context, &env, worker).with_context(|| {
+                        if !taskset_elem.requires.is_empty() {
+                            insert_tasks_context(
+                                &mut context,
+                                name,
+                                &required_results,
+                                &worker.name(),
+                            )?;
+                        }
+                        let result = task
+                            .run(&Some(name), &context, &env, worker)
+                            .and_then(|result| result.deny_continue())
+                            .with_context(|| {
                                 format!("taskset task: `{}`, worker: `{}`", name, worker.name())
                             })?;
                         if let Some(exception) = result.as_exception() {
                             warn!("Got exception: {:?}", exception);
+                        } else if let Some(value) = result.as_value() {
+                            let vars = result.as_context().unwrap_or_default().into_json();
+                            tasks_results
+                                .lock()
+                                .expect("taskset results lock is poisoned")
+                                .entry(name.to_string())
+                                .or_default()
+                                .insert(worker.name(), json!({ "result": value, "vars": vars }));
                         }
                     };
 
@@ -345,3 +401,75 @@ impl Runner {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results() -> BTreeMap<String, BTreeMap<String, Value>> {
+        let master = json!({ "result": "token", "vars": { "port": 6443 } });
+        BTreeMap::from([(
+            "setup-master".to_string(),
+            BTreeMap::from([("master".to_string(), master)]),
+        )])
+    }
+
+    #[test]
+    fn tasks_context_workers() {
+        let tasks = tasks_context(&results(), "master");
+        assert_eq!(tasks["setup-master"]["result"], "token");
+        assert_eq!(tasks["setup-master"]["vars"]["port"], 6443);
+        assert_eq!(tasks["setup-master"]["workers"]["master"]["result"], "token");
+
+        let tasks = tasks_context(&results(), "node-1");
+        assert_eq!(tasks["setup-master"]["result"], Value::Null);
+        assert_eq!(tasks["setup-master"]["workers"]["master"]["result"], "token");
+    }
+
+    #[test]
+    fn worker_scope_vars_override_manifest_vars() -> Result<()> {
+        crate::config::init_default();
+        let dir = crate::tmpdir::mktemp_dir()?;
+        let out = dir.join("out");
+        let manifest = format!(
+            r#"
+            [workers.host]
+            engine = "host"
+
+            [vars]
+            x = "manifest"
+
+            [taskset.set]
+            set = {{ vars = {{ x = "worker-set" }}, scope = "worker" }}
+
+            [taskset.show]
+            requires = ["set"]
+            shell.cmd = "echo x={{{{ x }}}} > {out}"
+            "#,
+            out = out.display()
+        );
+        fs::write(dir.join("LM.toml"), manifest)?;
+
+        let mut runner = Runner::from_manifest(dir.join("LM.toml"), &Context::new())?;
+        runner.run()?;
+        assert_eq!(fs::read_to_string(out)?, "x=worker-set\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn tasks_context_conflict() -> Result<()> {
+        let mut context = Context::new();
+        insert_tasks_context(&mut context, "setup-node", &results(), "node-1")?;
+        assert!(context.contains_key("tasks"));
+
+        let mut context = Context::new();
+        context.insert("tasks", &["user", "value"]);
+        let error = insert_tasks_context(&mut context, "setup-node", &results(), "node-1");
+        let error = error.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::TasksVarConflict(_))));
+        assert_eq!(context.get("tasks"), Some(&json!(["user", "value"])));
+
+        Ok(())
+    }
+}
@@ -28,7 +28,7 @@ impl Storage {
             let items = manifest_storage
                 .items
                 .as_ref()
-                .map(|i| i.list(&context))
+                .map(|i| i.list(&context, None))
                 .transpose()?
                 .unwrap_or_else(|| vec!["".to_string()]);
 
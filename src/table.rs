@@ -1,12 +1,17 @@
 use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 
-use anyhow::Result;
-use cmd_lib::run_fun;
+use anyhow::Context as AnyhowContext;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::error::Error;
+use crate::items::{command_out, manifest_path};
 use crate::render::Render;
 use crate::string_or_int::StringOrInt;
 use crate::template::Context;
+use crate::worker::Worker;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -17,11 +22,59 @@ pub enum TableFormat {
     Yaml,
 }
 
+impl TableFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => TableFormat::Csv,
+            Some("json") => TableFormat::Json,
+            Some("toml") => TableFormat::Toml,
+            Some("yaml" | "yml") => TableFormat::Yaml,
+            _ => bail!(Error::TableFileFormat(path.to_path_buf())),
+        };
+
+        Ok(format)
+    }
+
+    fn parse(&self, out: &str) -> Result<Vec<BTreeMap<String, StringOrInt>>> {
+        let table = match self {
+            TableFormat::Toml => match toml::from_str(out) {
+                Ok(table) => table,
+                Err(_) => toml::from_str::<BTreeMap<String, Vec<_>>>(out)?
+                    .into_values()
+                    .flatten()
+                    .collect(),
+            },
+            TableFormat::Json => serde_json::from_str(out)?,
+            TableFormat::Yaml => serde_yaml::from_str(out)?,
+            TableFormat::Csv => {
+                let mut table = vec![];
+                let mut rdr = csv::Reader::from_reader(out.as_bytes());
+                for result in rdr.deserialize() {
+                    let record: BTreeMap<String, StringOrInt> = result?;
+                    table.push(record)
+                }
+                table
+            }
+        };
+
+        Ok(table)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TableFile {
+    pub file: String,
+    pub format: Option<TableFormat>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct TableCommand {
     pub command: String,
     pub format: TableFormat,
+    #[serde(default)]
+    pub on_worker: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -30,6 +83,7 @@ pub struct TableCommand {
 pub enum Table {
     Maps(Vec<BTreeMap<String, StringOrInt>>),
     Command(TableCommand),
+    File(TableFile),
 }
 
 impl Default for Table {
@@ -39,7 +93,11 @@ impl Default for Table {
 }
 
 impl Table {
-    pub fn list(&self, context: &Context) -> Result<Vec<BTreeMap<String, String>>> {
+    pub fn list(
+        &self,
+        context: &Context,
+        worker: Option<&Worker>,
+    ) -> Result<Vec<BTreeMap<String, String>>> {
         let table: Vec<BTreeMap<String, StringOrInt>> = match self {
             Table::Maps(maps) => {
                 let mut new_maps = Vec::with_capacity(maps.len());
@@ -55,21 +113,18 @@ impl Table {
             }
             Table::Command(command) => {
                 let cmd = command.command.render(context, "list table command")?;
-                let out = run_fun!(sh -c $cmd)?;
-                match command.format {
-                    TableFormat::Toml => toml::from_str(&out)?,
-                    TableFormat::Json => serde_json::from_str(&out)?,
-                    TableFormat::Yaml => serde_yaml::from_str(&out)?,
-                    TableFormat::Csv => {
-                        let mut table = vec![];
-                        let mut rdr = csv::Reader::from_reader(out.as_bytes());
-                        for result in rdr.deserialize() {
-                            let record: BTreeMap<String, StringOrInt> = result?;
-                            table.push(record)
-                        }
-                        table
-                    }
-                }
+                let out = command_out(&cmd, command.on_worker, worker)?;
+                command.format.parse(&out)?
+            }
+            Table::File(file) => {
+                let path = manifest_path(context, &file.file.render(context, "list table file")?);
+                let format = match &file.format {
+                    Some(format) => format.to_owned(),
+                    None => TableFormat::from_path(&path)?,
+                };
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read table file `{}`", path.display()))?;
+                format.parse(&content)?
             }
         };
 
@@ -81,3 +136,31 @@ impl Table {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmpdir::mktemp_dir;
+
+    #[test]
+    fn file_formats() -> Result<()> {
+        let dir = mktemp_dir()?;
+        fs::write(dir.join("hosts.csv"), "name,port\na,1\nb,2\n")?;
+        fs::write(dir.join("hosts.toml"), "[[hosts]]\nname = \"a\"\nport = 1\n")?;
+        let mut context = Context::new();
+        context.insert("manifest_dir", &dir.to_string_lossy().to_string());
+
+        let table = toml::from_str::<Table>(r#"file = "hosts.csv""#)?.list(&context, None)?;
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[1]["name"], "b");
+        assert_eq!(table[1]["port"], "2");
+
+        let table = toml::from_str::<Table>(r#"file = "hosts.toml""#)?.list(&context, None)?;
+        assert_eq!(table[0]["name"], "a");
+
+        let table = toml::from_str::<Table>(r#"file = "hosts.txt""#)?;
+        assert!(table.list(&context, None).is_err());
+
+        Ok(())
+    }
+}
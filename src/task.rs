@@ -1,17 +1,19 @@
 use std::collections::BTreeMap;
 use std::num::NonZeroU32;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::Context as AnyhowContext;
-use anyhow::Result;
-use log::{info, warn};
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
 use rayon::iter::ParallelIterator;
 use rayon_cond::CondIterator;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::error::Error;
 use crate::fs_var::FsVar;
 use crate::items::Items;
 use crate::manifest::Tasklines;
@@ -19,8 +21,8 @@ use crate::render::Render;
 use crate::storage::Storages;
 use crate::table::Table;
 use crate::task_result::TaskResult;
-use crate::task_type::{CmdParams, TaskType};
-use crate::template::Context;
+use crate::task_type::{backoff_delay, check_backoff_factor, CmdParams, TaskType};
+use crate::template::{bind_worker, Context};
 use crate::vars::ExtVars;
 use crate::worker::Worker;
 
@@ -30,6 +32,8 @@ pub struct Env<'a> {
     pub storages: &'a Storages,
     pub tasklines: &'a Tasklines,
     pub workers: &'a Vec<Worker>,
+    /// Number of workers running the current taskset task.
+    pub task_workers: usize,
 }
 
 fn show_duration(duration: Duration) -> String {
@@ -51,6 +55,7 @@ pub struct TaskItemsTable {
     pub items: Items,
     #[serde(default = "default_task_items_table_items_var")]
     pub items_var: String,
+    pub items_key: Option<String>,
     #[serde(rename = "table_by_item")]
     pub table_by_item: Option<Table>,
 }
@@ -66,6 +71,24 @@ fn default_task_try_sleep() -> Duration {
     Duration::from_secs(1)
 }
 
+fn default_task_try_backoff_factor() -> f64 {
+    2.0
+}
+
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct TaskTryBackoff {
+    #[serde(default = "default_task_try_backoff_factor")]
+    factor: f64,
+    #[serde_as(
+        as = "Option<serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>>"
+    )]
+    #[serde(default)]
+    max: Option<Duration>,
+}
+
 #[serde_with::serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -76,6 +99,48 @@ pub struct TaskTry {
     #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>")]
     #[serde(default = "default_task_try_sleep")]
     sleep: Duration,
+    until: Option<String>,
+    backoff: Option<TaskTryBackoff>,
+    #[serde_as(
+        as = "Option<serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>>"
+    )]
+    #[serde(default)]
+    jitter: Option<Duration>,
+}
+
+impl TaskTry {
+    fn until_met(&self, result: &TaskResult, context: &Context) -> Result<bool> {
+        let (Some(until), Some(value)) = (&self.until, result.as_value()) else {
+            return Ok(true);
+        };
+        let mut context = context.to_owned();
+        context.insert("result", value);
+
+        let value = render_expr(until, &context, "try until")?;
+        match serde_json::from_str::<Value>(&value) {
+            Ok(Value::Bool(until)) => Ok(until),
+            _ => bail!(Error::UntilNotBool(until.to_string(), value)),
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Result<Duration> {
+        let mut delay = self.sleep;
+        if let Some(backoff) = &self.backoff {
+            check_backoff_factor(backoff.factor)?;
+            delay = backoff_delay(delay, backoff.factor, attempt - 1, backoff.max);
+        }
+        if let Some(jitter) = self.jitter {
+            delay = delay.saturating_add(jitter.mul_f64(rand::random::<f64>()));
+        }
+
+        Ok(delay)
+    }
+}
+
+/// Render a template boolean expression, e.g. `result == 'ok'`, as json.
+fn render_expr(expr: &str, context: &Context, place: &str) -> Result<String> {
+    let template = format!("{{% set value = {} %}}{{{{ value | json }}}}", expr);
+    template.render(context, place)
 }
 
 fn default_task_parallel() -> bool {
@@ -89,6 +154,8 @@ pub struct Task {
     #[serde(alias = "cond")]
     #[serde(alias = "if")]
     pub condition: Option<String>,
+    pub when: Option<String>,
+    pub if_cmd: Option<String>,
     #[serde(default)]
     pub clean_vars: bool,
     #[serde(default = "default_task_parallel")]
@@ -98,6 +165,7 @@ pub struct Task {
     pub vars: ExtVars,
     #[serde(default)]
     pub export_vars: Vec<String>,
+    pub register: Option<String>,
     #[serde(flatten)]
     pub items_table: Option<TaskItemsTable>,
     #[serde(flatten)]
@@ -107,6 +175,44 @@ pub struct Task {
 }
 
 impl Task {
+    fn skip_reason(&self, context: &Context, worker: &Worker) -> Result<Option<String>> {
+        if let Some(condition) = &self.condition {
+            let condition = condition.render(context, "task condition")?;
+            let skip = match condition.trim() {
+                "true" => false,
+                "false" => true,
+                _ => worker.shell(&condition, &CmdParams::default()).is_err(),
+            };
+            if skip {
+                return Ok(Some(format!("condition `{}` is false", condition.trim())));
+            }
+        }
+
+        if let Some(when) = &self.when {
+            let value = render_expr(when, context, "task when")?;
+            match serde_json::from_str::<Value>(&value) {
+                Ok(Value::Bool(true)) => {}
+                Ok(Value::Bool(false)) => {
+                    return Ok(Some(format!("when `{}` is false", when)));
+                }
+                _ => bail!(Error::WhenNotBool(when.to_string(), value)),
+            }
+        }
+
+        if let Some(if_cmd) = &self.if_cmd {
+            let if_cmd = if_cmd.render(context, "task if-cmd")?;
+            let mut params = CmdParams::quiet();
+            params.check = Some(false);
+            let out = worker.shell(&if_cmd, &params)?;
+            if !out.success() {
+                let rc = out.rc().map(|rc| rc.to_string()).unwrap_or("none".to_string());
+                return Ok(Some(format!("if-cmd `{}` returned code {}", if_cmd, rc)));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn run<S: AsRef<str>>(
         &self,
         name: &Option<S>,
@@ -114,14 +220,24 @@ impl Task {
         env: &Env,
         worker: &Worker,
     ) -> Result<TaskResult> {
-        let context = if self.clean_vars { Context::default() } else { context.to_owned() };
+        // templates are rendered on the threads of parallel items and rows too
+        let bound_worker = Arc::new(worker.to_owned());
+        let _binding = bind_worker(&bound_worker);
+        let context = if self.clean_vars {
+            Context::default()
+        } else {
+            // variables set with the `worker` scope override inherited ones
+            let mut context = context.to_owned();
+            context.extend(worker.vars());
+            context
+        };
 
         let items = self
             .items_table
             .as_ref()
-            .map(|i| i.items.list(&context))
+            .map(|i| i.items.values(&context, Some(worker)))
             .transpose()?
-            .unwrap_or_else(|| vec!["".to_string()]);
+            .unwrap_or_else(|| vec![Value::String("".to_string())]);
         let items_var = self
             .items_table
             .as_ref()
@@ -130,18 +246,31 @@ impl Task {
 
         let name = name.as_ref().map(|n| n.as_ref().to_string());
         let results =
-            CondIterator::new(items, self.parallel).map(|item| -> Result<(String, TaskResult)> {
+            CondIterator::new(items, self.parallel).map(|value| -> Result<(String, TaskResult)> {
+                let _binding = bind_worker(&bound_worker);
                 let table = self
                     .table
                     .as_ref()
-                    .map(|i| i.list(&context))
+                    .map(|i| i.list(&context, Some(worker)))
                     .transpose()?
                     .unwrap_or_else(|| vec![BTreeMap::new()]);
                 let mut context = context.to_owned();
-                context.insert(&items_var, &item);
+                let item = match &self.items_table {
+                    Some(items_table) => {
+                        items_table.items.insert(&mut context, &items_var, &value);
+                        match &items_table.items_key {
+                            Some(key) => key.render(&context, "task items-key")?,
+                            None => items_table.items.item_key(&value),
+                        }
+                    }
+                    None => {
+                        context.insert(&items_var, &value);
+                        Items::key(&value)
+                    }
+                };
                 if let Some(items_table) = &self.items_table {
                     if let Some(table_by_item) = &items_table.table_by_item {
-                        for row in table_by_item.list(&context)? {
+                        for row in table_by_item.list(&context, Some(worker))? {
                             if let Some(table_item) = row.get("item") {
                                 if table_item == &item {
                                     context.insert("row_by_item", &row);
@@ -153,22 +282,22 @@ impl Task {
 
                 let results = CondIterator::new(table, self.parallel)
                     .map(|row| -> Result<TaskResult> {
+                        let _binding = bind_worker(&bound_worker);
                         let mut context = context.to_owned();
                         context.insert("row", &row);
                         let task_vars = self.vars.render(&context, "task")?;
                         let vars_context = task_vars.vars()?.context()?;
                         context.extend(vars_context.to_owned());
-                        if let Some(condition) = &self.condition {
-                            let condition = condition.render(&context, "task condition")?;
-                            let skip = match condition.trim() {
-                                "true" => false,
-                                "false" => true,
-                                _ => worker.shell(condition, &CmdParams::default()).is_err(),
+                        if let Some(reason) = self.skip_reason(&context, worker)? {
+                            let task = match &name {
+                                Some(name) => {
+                                    format!("`{}` ", name.render(&context, "task name")?)
+                                }
+                                None => "".to_string(),
                             };
-                            if skip {
-                                let result = context.get("result").unwrap_or(&Value::Null);
-                                return Ok(result.to_owned().into());
-                            }
+                            debug!("Skip task {}on worker `{}`: {}", task, worker.name(), reason);
+                            let result = context.get("result").unwrap_or(&Value::Null);
+                            return Ok(result.to_owned().into());
                         }
                         if let Some(name) = &name {
                             let name = name.render(&context, "task name")?;
@@ -193,26 +322,53 @@ impl Task {
 
                         let start = Instant::now();
                         let mut attempts = "".to_string();
+                        if self.try_.is_some() {
+                            context.insert("attempt", &1);
+                        }
                         let mut res = self.task_type.run(&context, env, worker);
                         if let Some(try_) = &self.try_ {
-                            let mut final_attempt = 1;
-                            for attempt in 1..=try_.attempts.get() {
-                                final_attempt = attempt;
-                                if res.is_err() {
-                                    thread::sleep(try_.sleep);
-                                    if let Some(cleanup) = &try_.cleanup {
-                                        if cleanup.task.run(&context, env, worker).is_err() {
-                                            warn!("Cleanup command failed");
+                            let mut attempt = 1;
+                            let mut until = true;
+                            loop {
+                                let outcome = match &res {
+                                    Ok(result) => {
+                                        until = try_.until_met(result, &context)?;
+                                        if until {
+                                            "succeeded"
+                                        } else {
+                                            "until condition is not met"
                                         }
                                     }
-                                    res = self.task_type.run(&context, env, worker);
-                                } else {
+                                    Err(_) => "failed",
+                                };
+                                debug!(
+                                    "Attempt {} of task on worker `{}` {}",
+                                    attempt,
+                                    worker.name(),
+                                    outcome
+                                );
+                                if (res.is_ok() && until) || attempt > try_.attempts.get() {
                                     break;
                                 }
+
+                                thread::sleep(try_.delay(attempt)?);
+                                if let Some(cleanup) = &try_.cleanup {
+                                    if cleanup.task.run(&context, env, worker).is_err() {
+                                        warn!("Cleanup command failed");
+                                    }
+                                }
+                                attempt += 1;
+                                context.insert("attempt", &attempt);
+                                res = self.task_type.run(&context, env, worker);
                             }
 
-                            if final_attempt > 1 {
-                                attempts = format!("({} attempts)", final_attempt);
+                            if res.is_ok() && !until {
+                                let until = try_.until.as_deref().unwrap_or_default();
+                                res = Error::TryUntil(until.to_string(), attempt)
+                                    .result([("attempts", attempt)]);
+                            }
+                            if attempt > 1 {
+                                attempts = format!("({} attempts)", attempt);
                             }
                         }
                         let duration = start.elapsed();
@@ -251,6 +407,12 @@ impl Task {
                         if self.items_table.is_some() {
                             res = res.with_context(|| format!("item: `{}`", item));
                         }
+                        if let (Some(register), Ok(result)) = (&self.register, res.as_mut()) {
+                            if let Some(value) = result.register_value(duration) {
+                                let register = register.render(&context, "task register")?;
+                                result.add_vars(serde_json::json!({ register: value }));
+                            }
+                        }
                         let mut new_vars_context = Context::new();
                         for name in &self.export_vars {
                             if let Some(value) = &vars_context.get(name) {
@@ -277,6 +439,7 @@ impl Task {
                     let mut pairs = vec![];
                     for result in iterator {
                         let (item, result) = result?;
+                        let result = result.catch_continue();
                         if result.as_exception().is_some() {
                             return Ok(result);
                         }
@@ -290,6 +453,10 @@ impl Task {
             CondIterator::Parallel(iterator) => {
                 let results = iterator.collect::<Result<Vec<_>>>()?;
                 if self.items_table.is_some() {
+                    let results = results
+                        .into_iter()
+                        .map(|(item, result)| (item, result.catch_continue()))
+                        .collect::<Vec<_>>();
                     TaskResult::fold_items(&results)
                 } else {
                     results[0].1.to_owned()
@@ -308,3 +475,191 @@ impl Task {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_try(toml: &str) -> TaskTry {
+        toml::from_str(&format!("attempts = 5\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn try_delay() -> Result<()> {
+        let try_ = task_try("sleep = 0.5");
+        assert_eq!(try_.delay(1)?, Duration::from_millis(500));
+        assert_eq!(try_.delay(4)?, Duration::from_millis(500));
+
+        let try_ = task_try("sleep = 1\nbackoff = { factor = 2, max = 5 }");
+        let delays = (1..=5).map(|a| try_.delay(a)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs));
+
+        let try_ = task_try("backoff = {}");
+        assert_eq!(try_.delay(u32::MAX)?, Duration::from_secs(24 * 60 * 60));
+
+        let try_ = task_try("jitter = 0.5");
+        let delay = try_.delay(1)?;
+        assert!(delay >= Duration::from_secs(1) && delay < Duration::from_millis(1500));
+
+        for factor in ["0.5", "inf", "nan"] {
+            let try_ = task_try(&format!("backoff.factor = {factor}"));
+            let error = try_.delay(1).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(Error::BadBackoffFactor(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_until() -> Result<()> {
+        let context = Context::new();
+        let result = |value: &str| TaskResult::from(Value::String(value.to_string()));
+
+        let try_ = task_try("until = \"result == 'active'\"");
+        assert!(try_.until_met(&result("active"), &context)?);
+        assert!(!try_.until_met(&result("inactive"), &context)?);
+
+        let try_ = task_try("until = 'result'");
+        let error = try_.until_met(&result("active"), &context).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::UntilNotBool(..))));
+
+        assert!(task_try("").until_met(&result("any"), &context)?);
+
+        Ok(())
+    }
+
+    fn run_task(toml: &str, tasklines: &str) -> Result<TaskResult> {
+        let task = toml::from_str::<Task>(toml)?;
+        let tasklines = toml::from_str::<Tasklines>(tasklines)?;
+        let worker = Worker::test_host();
+        let (dir, storages) = (PathBuf::new(), Default::default());
+        let env = Env {
+            dir: &dir,
+            storages: &storages,
+            tasklines: &tasklines,
+            workers: &vec![],
+            task_workers: 1,
+        };
+        task.run(&None::<String>, &Context::new(), &env, &worker)
+    }
+
+    #[test]
+    fn continue_items() -> Result<()> {
+        let toml = r#"
+            items = [1, 2]
+            parallel = false
+            vars.n = "{{ item }}"
+            block.tasks = [{ continue.result = "{{ n }}" }, { error.msg = "not skipped" }]
+        "#;
+        let result = run_task(toml, "")?;
+        assert_eq!(result.as_value(), Some(&serde_json::json!({"1": "1", "2": "2"})));
+
+        let tasklines = r#"service = [
+            { continue = {}, when = "service == 'a'" },
+            { dummy.result = "{{ service }}" },
+        ]"#;
+        let result = run_task(
+            "items = ['a', 'b']\nvars.service = '{{ item }}'\nrun = 'service'",
+            tasklines,
+        )?;
+        assert_eq!(result.as_value(), Some(&serde_json::json!({"a": null, "b": "b"})));
+
+        Ok(())
+    }
+
+    #[test]
+    fn continue_outside_items() -> Result<()> {
+        let result = run_task("run = 'inner'", "inner = [{ continue = {} }]")?;
+        let error = result.deny_continue().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::ContinueOutsideItems)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn return_taskline() -> Result<()> {
+        let tasklines = "inner = [{ return.result = 1 }, { error.msg = 'not returned' }]";
+        let result = run_task("run = 'inner'", tasklines)?.deny_continue()?;
+        assert!(result.as_exception().is_none());
+        assert_eq!(result.as_value(), Some(&Value::from(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_parent_in_items() -> Result<()> {
+        let tasklines = r#"inner = [
+            { items = [1, 2], vars.n = "{{ item }}", set = { vars.x = "{{ n }}", scope = "parent" } },
+        ]"#;
+        let result = run_task("run = 'inner'", tasklines)?;
+        let vars = result.as_context().expect("vars should be set").into_json();
+        assert_eq!(vars["x"], serde_json::json!({"1": "1", "2": "2"}));
+
+        Ok(())
+    }
+
+    #[test]
+    fn clean_vars_skip_worker_vars() -> Result<()> {
+        let task = toml::from_str::<Task>("dummy.result = \"{{ x | default(value='none') }}\"")?;
+        let clean_task = Task { clean_vars: true, ..task.to_owned() };
+        let worker = Worker::test_host();
+        let mut vars = Context::new();
+        vars.insert("x", "worker");
+        worker.set_vars(vars);
+        let (dir, storages, tasklines) = (PathBuf::new(), Default::default(), Default::default());
+        let env = Env {
+            dir: &dir,
+            storages: &storages,
+            tasklines: &tasklines,
+            workers: &vec![],
+            task_workers: 1,
+        };
+
+        let result = task.run(&None::<String>, &Context::new(), &env, &worker)?;
+        assert_eq!(result.as_value(), Some(&Value::from("worker")));
+        let mut context = Context::new();
+        context.insert("x", "manifest");
+        let result = task.run(&None::<String>, &context, &env, &worker)?;
+        assert_eq!(result.as_value(), Some(&Value::from("worker")));
+        let result = clean_task.run(&None::<String>, &Context::new(), &env, &worker)?;
+        assert_eq!(result.as_value(), Some(&Value::from("none")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_reason_when() -> Result<()> {
+        let worker = Worker::test_host();
+        let mut context = Context::new();
+        context.insert("update", &true);
+        context.insert("os", "alt");
+
+        let task = toml::from_str::<Task>("dummy = {}\nwhen = \"update and os == 'alt'\"")?;
+        assert_eq!(task.skip_reason(&context, &worker)?, None);
+
+        let task = toml::from_str::<Task>("dummy = {}\nwhen = 'not update'")?;
+        let reason = task.skip_reason(&context, &worker)?;
+        assert_eq!(reason.as_deref(), Some("when `not update` is false"));
+
+        let task = toml::from_str::<Task>("dummy = {}\nwhen = 'os'")?;
+        let error = task.skip_reason(&context, &worker).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::WhenNotBool(..))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_reason_if_cmd() -> Result<()> {
+        let worker = Worker::test_host();
+        let context = Context::new();
+
+        let task = toml::from_str::<Task>("dummy = {}\nif-cmd = 'true'")?;
+        assert_eq!(task.skip_reason(&context, &worker)?, None);
+
+        let task = toml::from_str::<Task>("dummy = {}\nif-cmd = 'exit 3'")?;
+        let reason = task.skip_reason(&context, &worker)?;
+        assert_eq!(reason.as_deref(), Some("if-cmd `exit 3` returned code 3"));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::BTreeMap;
+use std::time::Duration;
 
+use anyhow::{bail, Result};
 use serde_json::{to_value, Value};
 
+use crate::error::Error;
 use crate::exception::Exception;
 use crate::template::Context;
 
@@ -17,10 +20,36 @@ pub struct TaskResult {
 }
 
 impl TaskResult {
+    fn merge_vars(&mut self, key: &str, vars: Value) {
+        if let Either::Value(v) = &mut self.either {
+            let object = v.as_object_mut().expect("internal value should be an object");
+            match (object.get_mut(key), vars) {
+                (Some(Value::Object(old)), Value::Object(new)) => old.extend(new),
+                (_, vars) => {
+                    object.insert(key.to_string(), vars);
+                }
+            }
+        }
+    }
+
     pub fn add_vars(&mut self, vars: Value) {
+        self.merge_vars("vars", vars);
+    }
+
+    pub fn add_parent_vars(&mut self, vars: Value) {
+        self.merge_vars("parent_vars", vars);
+    }
+
+    pub fn lift_parent_vars(mut self) -> Self {
         if let Either::Value(v) = &mut self.either {
-            v.as_object_mut().unwrap().insert("vars".to_string(), vars);
+            let object = v.as_object_mut().expect("internal value should be an object");
+            object.remove("vars");
+            if let Some(vars) = object.remove("parent_vars") {
+                object.insert("vars".to_string(), vars);
+            }
         }
+
+        self
     }
 
     pub fn as_value(&self) -> Option<&Value> {
@@ -36,6 +65,21 @@ impl TaskResult {
         }
     }
 
+    pub fn catch_continue(self) -> Self {
+        match self.either {
+            Either::Exception(Exception::Continue { result }) => result.into(),
+            _ => self,
+        }
+    }
+
+    /// `continue` outside of items has no iteration to skip.
+    pub fn deny_continue(self) -> Result<Self> {
+        match self.either {
+            Either::Exception(Exception::Continue { .. }) => bail!(Error::ContinueOutsideItems),
+            _ => Ok(self),
+        }
+    }
+
     pub fn as_exception(&self) -> Option<&Exception> {
         match &self.either {
             Either::Exception(exception) => Some(exception),
@@ -43,6 +87,39 @@ impl TaskResult {
         }
     }
 
+    pub fn set_register(&mut self, register: Value) {
+        if let Either::Value(v) = &mut self.either {
+            let object = v.as_object_mut().expect("internal value should be an object");
+            object.insert("register".to_string(), register);
+        }
+    }
+
+    pub fn register_value(&self, duration: Duration) -> Option<Value> {
+        let Either::Value(value) = &self.either else {
+            return None;
+        };
+        let object = value.as_object().expect("internal value should be an object");
+        match object.get("register") {
+            Some(Value::Object(register)) => {
+                let mut register = register.to_owned();
+                register.insert("duration".to_string(), duration.as_secs_f64().into());
+                Some(Value::Object(register))
+            }
+            _ => object.get("value").cloned(),
+        }
+    }
+
+    pub fn as_parent_context(&self) -> Option<Context> {
+        match &self.either {
+            Either::Value(value) => value
+                .as_object()
+                .expect("internal value should be an object")
+                .get("parent_vars")
+                .and_then(|vars| Context::from_value(vars.to_owned()).ok()),
+            _ => None,
+        }
+    }
+
     pub fn as_context(&self) -> Option<Context> {
         match &self.either {
             Either::Value(value) => {
@@ -60,7 +137,7 @@ impl TaskResult {
 
     pub fn fold_vec(results: &[Self]) -> Self {
         let mut value_array = vec![];
-        let mut vars_array = vec![];
+        let mut vars_arrays: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
         for result in results {
             match &result.either {
                 Either::Value(v) => {
@@ -73,8 +150,10 @@ impl TaskResult {
                             .to_owned(),
                     );
 
-                    if let Some(vars) = whole_value.get("vars") {
-                        vars_array.push(vars.to_owned());
+                    for key in ["vars", "parent_vars"] {
+                        if let Some(vars) = whole_value.get(key) {
+                            vars_arrays.entry(key).or_default().push(vars.to_owned());
+                        }
                     }
                 }
                 Either::Exception(_) => return result.to_owned(),
@@ -83,26 +162,30 @@ impl TaskResult {
 
         let mut result: Self = Value::Array(value_array).into();
 
-        let mut vars_object: BTreeMap<String, Vec<Value>> = BTreeMap::new();
-        for vars in vars_array {
-            let vars = vars.as_object().expect("internal value vars should be an object");
-            for (name, value) in vars {
-                vars_object
-                    .entry(name.to_owned())
-                    .and_modify(|e| {
-                        e.push(value.to_owned());
-                    })
-                    .or_insert_with(|| vec![value.to_owned()]);
+        for (key, vars_array) in vars_arrays {
+            let mut vars_object: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+            for vars in vars_array {
+                let vars = vars.as_object().expect("internal value vars should be an object");
+                for (name, value) in vars {
+                    vars_object
+                        .entry(name.to_owned())
+                        .and_modify(|e| {
+                            e.push(value.to_owned());
+                        })
+                        .or_insert_with(|| vec![value.to_owned()]);
+                }
             }
+            let vars = to_value(vars_object).expect("BTreeMap with string keys is valid value");
+            result.merge_vars(key, vars);
         }
-        result.add_vars(to_value(vars_object).expect("BTreeMap with string keys is valid value"));
 
         result
     }
 
     pub fn fold_items(results: &[(String, Self)]) -> Self {
         let mut value_object = serde_json::Map::new();
-        let mut items_vars_object = serde_json::Map::new();
+        let mut items_vars_objects: BTreeMap<&str, serde_json::Map<String, Value>> =
+            BTreeMap::new();
         for (item, result) in results {
             match &result.either {
                 Either::Value(whole_value) => {
@@ -117,8 +200,13 @@ impl TaskResult {
                             .to_owned(),
                     );
 
-                    if let Some(vars) = whole_value.get("vars") {
-                        items_vars_object.insert(item.to_string(), vars.to_owned());
+                    for key in ["vars", "parent_vars"] {
+                        if let Some(vars) = whole_value.get(key) {
+                            items_vars_objects
+                                .entry(key)
+                                .or_default()
+                                .insert(item.to_string(), vars.to_owned());
+                        }
                     }
                 }
                 Either::Exception(_) => return result.to_owned(),
@@ -127,19 +215,22 @@ impl TaskResult {
 
         let mut result: Self = Value::Object(value_object).into();
 
-        let mut vars_object: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
-        for (item, vars) in items_vars_object {
-            let vars = vars.as_object().expect("internal value vars should be an object");
-            for (name, value) in vars {
-                vars_object
-                    .entry(name.to_owned())
-                    .and_modify(|e| {
-                        e.insert(item.to_string(), value.to_owned());
-                    })
-                    .or_insert_with(|| BTreeMap::from([(item.to_string(), value.to_owned())]));
+        for (key, items_vars_object) in items_vars_objects {
+            let mut vars_object: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
+            for (item, vars) in items_vars_object {
+                let vars = vars.as_object().expect("internal value vars should be an object");
+                for (name, value) in vars {
+                    vars_object
+                        .entry(name.to_owned())
+                        .and_modify(|e| {
+                            e.insert(item.to_string(), value.to_owned());
+                        })
+                        .or_insert_with(|| BTreeMap::from([(item.to_string(), value.to_owned())]));
+                }
             }
+            let vars = to_value(vars_object).expect("BTreeMap with string keys is valid value");
+            result.merge_vars(key, vars);
         }
-        result.add_vars(to_value(vars_object).expect("BTreeMap with string keys is valid value"));
 
         result
     }
@@ -158,3 +249,40 @@ impl From<Exception> for TaskResult {
         TaskResult { either: Either::Exception(exception) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn result(value: i32) -> TaskResult {
+        let mut result: TaskResult = Value::from(value).into();
+        result.add_vars(json!({ "v": value }));
+        result.add_parent_vars(json!({ "p": value * 10 }));
+        result
+    }
+
+    #[test]
+    fn fold_vec_vars() {
+        let folded = TaskResult::fold_vec(&[result(1), result(2)]);
+        assert_eq!(folded.as_value(), Some(&json!([1, 2])));
+        assert_eq!(folded.as_context().unwrap().into_json(), json!({ "v": [1, 2] }));
+        assert_eq!(folded.as_parent_context().unwrap().into_json(), json!({ "p": [10, 20] }));
+    }
+
+    #[test]
+    fn fold_items_vars() {
+        let folded =
+            TaskResult::fold_items(&[("a".to_string(), result(1)), ("b".to_string(), result(2))]);
+        assert_eq!(folded.as_value(), Some(&json!({ "a": 1, "b": 2 })));
+        assert_eq!(folded.as_context().unwrap().into_json(), json!({ "v": { "a": 1, "b": 2 } }));
+        assert_eq!(
+            folded.as_parent_context().unwrap().into_json(),
+            json!({ "p": { "a": 10, "b": 20 } })
+        );
+
+        let folded = TaskResult::fold_items(&[("a".to_string(), Value::from(1).into())]);
+        assert!(folded.as_parent_context().is_none());
+    }
+}
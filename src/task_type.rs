@@ -1,28 +1,464 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Context as AnyhowContext;
 use anyhow::{bail, Result};
+use glob::Pattern;
+use lazy_static::lazy_static;
 use log::{debug, info, log, trace, warn, LevelFilter};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 
-use crate::cmd::CmdOut;
+use crate::cmd::{Cmd, CmdOut};
 use crate::config::CONFIG;
 use crate::engine::ExistsAction;
 use crate::error::Error;
 use crate::exception::Exception;
+use crate::files::walk_files;
+use crate::manifest::TasklineElem;
 use crate::matches::Matches;
 use crate::module;
-use crate::quote::quote;
+use crate::quote::{quote, quote_args};
 use crate::render::Render;
 use crate::runner::Runner;
+use crate::string_or_int::StringOrInt;
 use crate::task::Env;
 use crate::task_result::TaskResult;
 use crate::taskline::Taskline;
-use crate::template::Context;
-use crate::vars::{Var, Vars};
+use crate::template::{unbind_worker, Context, FileRenderer, Regex};
+use crate::tmpdir::mktemp_dir;
+use crate::vars::{ExtVars, Var, Vars};
 use crate::worker::Worker;
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        let name = path.to_string_lossy();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Self::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(Self::TarZst)
+        } else if [".zip", ".7z", ".rar", ".xz", ".bz2", ".gz", ".zst"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+        {
+            bail!(Error::ArchiveFormatUnsupported(path.to_owned()))
+        } else {
+            Ok(Self::Tar)
+        }
+    }
+
+    fn tar_args(&self) -> Vec<String> {
+        match self {
+            Self::Tar => vec![],
+            Self::TarGz => vec!["--gzip".to_string()],
+            Self::TarZst => vec!["--zstd".to_string()],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct ArchiveType {
+    paths: Vec<PathBuf>,
+    #[serde(alias = "dest")]
+    #[serde(alias = "destination")]
+    dst: PathBuf,
+    format: Option<ArchiveFormat>,
+    chdir: Option<PathBuf>,
+    #[serde(default)]
+    host: bool,
+    fetch: Option<PathBuf>,
+}
+
+impl ArchiveType {
+    pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<Value> {
+        let paths = self.paths.render(context, "paths in archive task")?;
+        let mut dst = self.dst.render(context, "dst in archive task")?;
+        let mut chdir = self.chdir.render(context, "chdir in archive task")?;
+        if self.host {
+            dst = env.dir.join(dst);
+            chdir = Some(env.dir.join(chdir.unwrap_or_default()));
+        }
+        let format = match &self.format {
+            Some(format) => format.to_owned(),
+            None => ArchiveFormat::from_path(&dst)?,
+        };
+
+        let mut args = vec!["tar".to_string(), "-c".to_string()];
+        args.extend(format.tar_args());
+        args.extend(["-f".to_string(), dst.to_string_lossy().to_string()]);
+        if let Some(chdir) = chdir {
+            args.extend(["-C".to_string(), chdir.to_string_lossy().to_string()]);
+        }
+        args.push("--".to_string());
+        args.extend(paths.iter().map(|p| p.to_string_lossy().to_string()));
+
+        if self.host {
+            let out = Cmd::from_args_str(&args).run()?;
+            if !out.success() {
+                let error = Error::CommandFailedExitCode(quote_args(&args)?);
+                return error.result([("stderr", out.stderr().trim_end())]);
+            }
+        } else {
+            worker.exec(&args, &CmdParams::quiet())?;
+            if let Some(fetch) = &self.fetch {
+                let fetch = env.dir.join(fetch.render(context, "fetch in archive task")?);
+                worker.get(&dst, &fetch)?;
+                return Ok(Value::String(fetch.to_string_lossy().to_string()));
+            }
+        }
+
+        Ok(Value::String(dst.to_string_lossy().to_string()))
+    }
+}
+
+fn run_tasks(
+    tasks: &[TasklineElem],
+    place: &str,
+    context: &mut Context,
+    env: &Env,
+    worker: &Worker,
+) -> Result<TaskResult> {
+    let mut value = Value::Null;
+    let mut vars = Context::new();
+    let mut parent_vars = Context::new();
+    for (iter, task) in tasks.iter().enumerate() {
+        let result = task
+            .task
+            .run(&task.name, context, env, worker)
+            .with_context(|| format!("{}, number: `{}`", place, iter))?;
+
+        if let Some(v) = result.as_value() {
+            if let Some(vars_context) = result.as_context() {
+                context.extend(vars_context.to_owned());
+                vars.extend(vars_context);
+            }
+            if let Some(parent_context) = result.as_parent_context() {
+                context.extend(parent_context.to_owned());
+                parent_vars.extend(parent_context);
+            }
+            value = v.to_owned();
+            context.insert("result", &value);
+        } else if result.as_exception().is_some() {
+            return Ok(result);
+        }
+    }
+
+    let mut result: TaskResult = value.into();
+    result.add_vars(vars.into_json());
+    result.add_parent_vars(parent_vars.into_json());
+
+    Ok(result)
+}
+
+fn error_vars(error: &anyhow::Error) -> (String, BTreeMap<String, String>) {
+    let mut msgs = vec![];
+    let mut error_context = BTreeMap::new();
+    for cause in error.chain() {
+        match cause.to_string() {
+            msg if msg.starts_with("taskset task: ") || msg.starts_with("taskline: ") => {}
+            msg if msg.starts_with("item: ") => {}
+            msg if msg.starts_with("context_json: ") => {
+                let pairs = msg.trim_start_matches("context_json: ");
+                if let Ok(pairs) = serde_json::from_str::<Vec<(String, String)>>(pairs) {
+                    error_context.extend(pairs);
+                }
+            }
+            msg => msgs.push(msg),
+        }
+    }
+
+    (msgs.join(": "), error_context)
+}
+
+const ASSERT_KEYWORDS: &[&str] =
+    &["and", "false", "False", "in", "is", "none", "None", "not", "or", "true", "True"];
+
+fn assert_vars(that: &str, context: &Context) -> Vec<(String, String)> {
+    lazy_static! {
+        static ref STRING_RE: Regex = Regex::new(r#""[^"]*"|'[^']*'|`[^`]*`"#).unwrap();
+        static ref IDENT_RE: Regex = Regex::new(r"([|.]\s*)?\b([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+    }
+    let that = STRING_RE.replace_all(that, "\"\"");
+    let mut vars = vec![];
+    for caps in IDENT_RE.captures_iter(&that) {
+        let name = &caps[2];
+        if caps.get(1).is_some() || ASSERT_KEYWORDS.contains(&name) {
+            continue;
+        }
+        if vars.iter().any(|(n, _)| n == name) {
+            continue;
+        }
+        if let Some(value) = context.get(name) {
+            vars.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    vars
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct AssertType {
+    that: Vec<String>,
+    msg: Option<String>,
+    success_msg: Option<String>,
+    #[serde(default)]
+    quiet: bool,
+}
+
+impl AssertType {
+    pub fn run(&self, context: &Context) -> Result<Value> {
+        let mut failed = vec![];
+        for that in &self.that {
+            let template = format!("{{% if {} %}}true{{% else %}}false{{% endif %}}", that);
+            if template.render(context, "that in assert task")? != "true" {
+                failed.push(that);
+            }
+        }
+
+        if failed.is_empty() {
+            if !self.quiet {
+                let msg = self.success_msg.render(context, "success-msg in assert task")?;
+                info!("{}", msg.unwrap_or_else(|| "All assertions passed".to_string()));
+            }
+            return Ok(context.get("result").cloned().unwrap_or(Value::Null));
+        }
+
+        let msg = self.msg.render(context, "msg in assert task")?;
+        let msg = msg.unwrap_or_else(|| "assertion failed".to_string());
+        let mut error_context = vec![];
+        for that in &failed {
+            error_context.push(("that".to_string(), that.to_string()));
+            error_context.extend(assert_vars(that, context));
+        }
+
+        Error::AssertFailed(msg).result(error_context)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockType {
+    tasks: Vec<TasklineElem>,
+    #[serde(default)]
+    rescue: Vec<TasklineElem>,
+    #[serde(default)]
+    always: Vec<TasklineElem>,
+}
+
+impl BlockType {
+    pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<TaskResult> {
+        let taskline = context.get("taskline").and_then(|t| t.as_str()).unwrap_or_default();
+        let place = |section| format!("taskline: `{}`, block {}", taskline, section);
+        let mut context = context.to_owned();
+
+        let mut result = run_tasks(&self.tasks, &place("tasks"), &mut context, env, worker);
+        if let Err(error) = &result {
+            if !self.rescue.is_empty() {
+                let (msg, error_context) = error_vars(error);
+                debug!("Rescue block on worker `{}` after error: {}", worker.name(), msg);
+                context.insert("error", &msg);
+                context.insert("error_context", &error_context);
+                result = run_tasks(&self.rescue, &place("rescue"), &mut context, env, worker);
+            }
+        }
+
+        if !self.always.is_empty() {
+            let always = run_tasks(&self.always, &place("always"), &mut context, env, worker);
+            match (&result, always) {
+                // the original error is the root cause, the always error is a context
+                (Err(_), Err(always_error)) => {
+                    return result
+                        .with_context(|| format!("block always failed: {always_error:#}"))
+                }
+                (Ok(_), Err(always_error)) => return Err(always_error),
+                (Ok(result), Ok(always)) if result.as_exception().is_none() => return Ok(always),
+                _ => {}
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EditFileState {
+    Absent,
+    #[default]
+    Present,
+}
+
+/// Index to insert new lines at. Anchors are regexes, the new lines are placed
+/// after the last line matched `insert_after` or before the first line matched
+/// `insert_before`. Special anchors `EOF` and `BOF` mean end and beginning of
+/// the file. Without anchors or when they do not match, insert at the end.
+fn edit_file_insert_index(
+    lines: &[String],
+    insert_after: &Option<String>,
+    insert_before: &Option<String>,
+) -> Result<usize> {
+    if let Some(after) = insert_after {
+        if after != "EOF" {
+            let re = Regex::new(after)?;
+            if let Some(index) = lines.iter().rposition(|l| re.is_match(l)) {
+                return Ok(index + 1);
+            }
+        }
+    } else if let Some(before) = insert_before {
+        if before == "BOF" {
+            return Ok(0);
+        }
+        let re = Regex::new(before)?;
+        if let Some(index) = lines.iter().position(|l| re.is_match(l)) {
+            return Ok(index);
+        }
+    }
+
+    Ok(lines.len())
+}
+
+/// Join edited lines with the line ending of the original content, keeping a missing final newline.
+fn edit_file_join(lines: &[String], content: &str) -> String {
+    let newline = match content.find('\n') {
+        Some(index) if content[..index].ends_with('\r') => "\r\n",
+        _ => "\n",
+    };
+    let mut joined = lines.join(newline);
+    if !lines.is_empty() && (content.is_empty() || content.ends_with('\n')) {
+        joined.push_str(newline);
+    }
+
+    joined
+}
+
+fn edit_file_read(worker: &Worker, path: &Path, create: bool) -> Result<String> {
+    let mut params = CmdParams::quiet();
+    params.check = Some(false);
+    let out = worker.exec(&["cat", "--", &path.to_string_lossy()], &params)?;
+    if out.success() {
+        Ok(out.stdout())
+    } else if create {
+        Ok("".to_string())
+    } else {
+        Error::EditFileRead(path.to_owned()).result([("stderr", out.stderr().trim_end())])
+    }
+}
+
+fn edit_file_write(worker: &Worker, path: &Path, content: String) -> Result<()> {
+    let path_quoted = quote(path.to_string_lossy())?;
+    let mut params = CmdParams::quiet();
+    params.stdin = Some(content);
+    worker.shell(format!("cat > {path_quoted}"), &params)?;
+
+    Ok(())
+}
+
+fn default_block_in_file_marker() -> String {
+    "# {mark} MANAGED BLOCK".to_string()
+}
+
+fn default_block_in_file_marker_begin() -> String {
+    "BEGIN".to_string()
+}
+
+fn default_block_in_file_marker_end() -> String {
+    "END".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockInFileType {
+    #[serde(alias = "dest")]
+    path: PathBuf,
+    #[serde(default)]
+    block: String,
+    #[serde(default = "default_block_in_file_marker")]
+    marker: String,
+    #[serde(default = "default_block_in_file_marker_begin")]
+    marker_begin: String,
+    #[serde(default = "default_block_in_file_marker_end")]
+    marker_end: String,
+    #[serde(default)]
+    state: EditFileState,
+    insert_after: Option<String>,
+    insert_before: Option<String>,
+    #[serde(default)]
+    create: bool,
+}
+
+impl BlockInFileType {
+    fn edit(&self, content: &str) -> Result<String> {
+        let begin = self.marker.replace("{mark}", &self.marker_begin);
+        let end = self.marker.replace("{mark}", &self.marker_end);
+        let mut lines = content.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        let mut block = vec![];
+        if let EditFileState::Present = self.state {
+            block.push(begin.to_string());
+            block.extend(self.block.lines().map(|l| l.to_string()));
+            block.push(end.to_string());
+        }
+
+        let begin_index = lines.iter().position(|l| l == &begin);
+        let end_index =
+            begin_index.and_then(|b| lines[b..].iter().position(|l| l == &end).map(|e| b + e));
+        if let (Some(begin_index), Some(end_index)) = (begin_index, end_index) {
+            lines.splice(begin_index..=end_index, block);
+        } else if !block.is_empty() {
+            let index = edit_file_insert_index(&lines, &self.insert_after, &self.insert_before)?;
+            lines.splice(index..index, block);
+        }
+
+        Ok(edit_file_join(&lines, content))
+    }
+
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<Value> {
+        let block_in_file = self.render(context, "block-in-file task")?;
+        let content = edit_file_read(worker, &block_in_file.path, self.create)?;
+        let new_content = block_in_file.edit(&content)?;
+        let changed = new_content != content;
+        if changed {
+            edit_file_write(worker, &block_in_file.path, new_content)?;
+        }
+
+        Ok(Value::Bool(changed))
+    }
+}
+
+impl Render for BlockInFileType {
+    fn render<S: AsRef<str>>(&self, context: &Context, place: S) -> Result<Self> {
+        let place = place.as_ref();
+        let path = self.path.render(context, format!("path in {}", place))?;
+        let block = self.block.render(context, format!("block in {}", place))?;
+        let marker = self.marker.render(context, format!("marker in {}", place))?;
+        let insert_after =
+            self.insert_after.render(context, format!("insert-after in {}", place))?;
+        let insert_before =
+            self.insert_before.render(context, format!("insert-before in {}", place))?;
+        Ok(Self { path, block, marker, insert_after, insert_before, ..self.to_owned() })
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct BreakType {
@@ -32,6 +468,14 @@ pub struct BreakType {
     pub result: Option<Value>,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContinueType {
+    #[serde(default)]
+    pub result: Option<Value>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
@@ -124,6 +568,7 @@ pub enum FileTypeSource {
     Src(PathBuf),
     #[serde(alias = "contents")]
     Content(String),
+    Template(PathBuf),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -136,16 +581,475 @@ pub struct FileType {
     pub source: FileTypeSource,
     pub chown: Option<String>,
     pub chmod: Option<String>,
+    #[serde(default)]
+    pub backup: bool,
+    #[serde(default)]
+    pub sync: bool,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("Failed to open file `{}`", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Marker printed instead of checksums if the worker has no `sha256sum`.
+const NO_SHA256SUM: &str = "no-sha256sum";
+
+/// State of a destination file on the worker.
+#[derive(Debug, PartialEq)]
+enum RemoteFile {
+    Absent,
+    Directory,
+    /// The file exists, the checksum is unknown if the worker has no `sha256sum`.
+    Exists(Option<String>),
+}
+
+impl RemoteFile {
+    fn upload_needed(&self, local_sha256: &str) -> bool {
+        !matches!(self, Self::Exists(Some(sha256)) if sha256 == local_sha256)
+    }
+}
+
+/// Files to upload and files to remove to sync a remote directory with a local one.
+fn sync_plan(
+    local: &BTreeMap<String, String>,
+    remote: &BTreeMap<String, Option<String>>,
+    excluded: impl Fn(&str) -> bool,
+) -> (Vec<String>, Vec<String>) {
+    let upload = local
+        .iter()
+        .filter(|(name, sha256)| remote.get(*name) != Some(&Some(sha256.to_string())))
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    let remove = remote
+        .keys()
+        .filter(|name| !local.contains_key(*name) && !excluded(name))
+        .cloned()
+        .collect();
+
+    (upload, remove)
+}
+
+/// `find` tests matching files with an owner or a mode different from `chown` and `chmod`.
+/// Returns `None` if the attributes can't be checked (e.g. a symbolic mode).
+fn attrs_differ_tests(chown: Option<&str>, chmod: Option<&str>) -> Result<Option<String>> {
+    let mut tests = vec![];
+    if let Some(chown) = chown {
+        let (user, group) = chown.split_once(':').unwrap_or((chown, ""));
+        if chown.ends_with(':') && !user.is_empty() {
+            // the group is the login group of the user
+            return Ok(None);
+        }
+        if !user.is_empty() {
+            tests.push(format!("! -user {}", quote(user)?));
+        }
+        if !group.is_empty() {
+            tests.push(format!("! -group {}", quote(group)?));
+        }
+    }
+    if let Some(chmod) = chmod {
+        if chmod.is_empty() || !chmod.chars().all(|c| c.is_digit(8)) {
+            return Ok(None);
+        }
+        tests.push(format!("! -perm {chmod}"));
+    }
+
+    Ok(Some(tests.join(" -o ")))
+}
+
+impl FileType {
+    fn remote_file(worker: &Worker, dst: &Path) -> Result<RemoteFile> {
+        let dst_quoted = quote(dst.to_string_lossy())?;
+        let cmd = format!(
+            "if [ ! -e {dst_quoted} ]; then echo absent; \
+             elif [ -d {dst_quoted} ]; then echo directory; \
+             elif command -v sha256sum >/dev/null 2>&1; then sha256sum -- {dst_quoted}; \
+             else echo {NO_SHA256SUM}; fi"
+        );
+        let out = worker.shell(cmd, &CmdParams::quiet())?;
+
+        Ok(match out.stdout().split_whitespace().next() {
+            Some("absent") | None => RemoteFile::Absent,
+            Some("directory") => RemoteFile::Directory,
+            Some(NO_SHA256SUM) => RemoteFile::Exists(None),
+            Some(sha256) => RemoteFile::Exists(Some(sha256.to_string())),
+        })
+    }
+
+    fn remote_sha256_dir(worker: &Worker, dst: &Path) -> Result<BTreeMap<String, Option<String>>> {
+        let dst_quoted = quote(dst.to_string_lossy())?;
+        let cmd = format!(
+            "cd {dst_quoted} 2>/dev/null || exit 0; \
+             if command -v sha256sum >/dev/null 2>&1; then find . -type f -exec sha256sum {{}} +; \
+             else echo {NO_SHA256SUM}; find . -type f; fi"
+        );
+        let out = worker.shell(cmd, &CmdParams::quiet())?;
+        let stdout = out.stdout();
+
+        let mut lines = stdout.lines().peekable();
+        let checksums = lines.next_if_eq(&NO_SHA256SUM).is_none();
+
+        let mut hashes = BTreeMap::new();
+        for line in lines {
+            let (hash, name) = if checksums {
+                match line.split_once("  ") {
+                    Some((hash, name)) => (Some(hash.to_string()), name),
+                    None => continue,
+                }
+            } else {
+                (None, line)
+            };
+            let name = name.strip_prefix("./").unwrap_or(name);
+            hashes.insert(name.to_string(), hash);
+        }
+
+        Ok(hashes)
+    }
+
+    fn backup(worker: &Worker, dst: &Path) -> Result<()> {
+        let dst_quoted = quote(dst.to_string_lossy())?;
+        let cmd = format!("cp -a {dst_quoted} {dst_quoted}.$(date +%Y%m%d%H%M%S)");
+        worker.shell(cmd, &CmdParams::quiet())?;
+
+        Ok(())
+    }
+
+    fn sync_dir(&self, worker: &Worker, src: &Path, dst: &Path) -> Result<bool> {
+        let exclude =
+            self.exclude.iter().map(|e| Pattern::new(e)).collect::<Result<Vec<_>, _>>()?;
+        let excluded = |name: &str| exclude.iter().any(|p| p.matches(name));
+
+        let mut local = BTreeMap::new();
+        for file in walk_files(src)? {
+            let name = file.strip_prefix(src)?.to_string_lossy().to_string();
+            if !excluded(&name) {
+                local.insert(name, sha256_file(&file)?);
+            }
+        }
+        let remote = Self::remote_sha256_dir(worker, dst)?;
+        if remote.values().any(|hash| hash.is_none()) {
+            warn!(
+                "No `sha256sum` on worker `{}`, upload `{}` without checking",
+                worker.name(),
+                dst.display()
+            );
+        }
+        let (upload, remove) = sync_plan(&local, &remote, excluded);
+
+        for name in &upload {
+            let path = dst.join(name);
+            if let Some(parent) = path.parent() {
+                worker.exec(&["mkdir", "-p", &parent.to_string_lossy()], &CmdParams::quiet())?;
+            }
+            debug!("Upload `{}` to worker `{}`", path.display(), worker.name());
+            worker.copy(src.join(name), &path)?;
+        }
+
+        if !remove.is_empty() {
+            let remove = remove
+                .iter()
+                .map(|n| dst.join(n).to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            debug!("Remove `{}` from worker `{}`", remove.join("`, `"), worker.name());
+            let mut args = vec!["rm".to_string(), "-f".to_string(), "--".to_string()];
+            args.extend(remove);
+            worker.exec(&args, &CmdParams::quiet())?;
+        }
+
+        Ok(!upload.is_empty() || !remove.is_empty())
+    }
+
+    fn attrs_differ(&self, worker: &Worker, dst: &Path) -> Result<bool> {
+        let Some(tests) = attrs_differ_tests(self.chown.as_deref(), self.chmod.as_deref())? else {
+            return Ok(true);
+        };
+        let dst_quoted = quote(dst.to_string_lossy())?;
+        let out = worker.shell(
+            format!("find {dst_quoted} \\( {tests} \\) -print -quit"),
+            &CmdParams::quiet(),
+        )?;
+
+        Ok(!out.stdout().trim().is_empty())
+    }
+
+    pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<TaskResult> {
+        let dst = self.dst.render(context, "file task dst")?;
+        let (src, contents) = match &self.source {
+            FileTypeSource::Src(src) => (Some(src.render(context, "file task src")?), None),
+            FileTypeSource::Content(contents) => {
+                (None, Some(contents.render(context, "file task contents")?))
+            }
+            FileTypeSource::Template(template) => {
+                let template = env.dir.join(template.render(context, "file task template")?);
+                (Some(render_template_path(&template, context)?), None)
+            }
+        };
+
+        let recursive = src.as_ref().is_some_and(|s| s.is_dir());
+        let not_applicable = |option: &str, place: &str| -> Result<()> {
+            bail!(Error::FileOptionNotApplicable(option.to_string(), place.to_string()))
+        };
+        if self.backup && recursive {
+            not_applicable("backup", "with a file `src`")?;
+        }
+        if self.sync && !recursive {
+            not_applicable("sync", "with a directory `src`")?;
+        }
+        if !self.exclude.is_empty() && !self.sync {
+            not_applicable("exclude", "with `sync`")?;
+        }
+
+        let mut target = dst.to_owned();
+        let mut changed = true;
+        if let Some(src) = src.as_ref().filter(|_| recursive) {
+            if self.sync {
+                changed = self.sync_dir(worker, src, &dst)?;
+            } else {
+                worker.copy(src, &dst)?;
+            }
+        } else {
+            let local_sha256 = match (&src, &contents) {
+                (Some(src), _) => sha256_file(src)?,
+                (None, Some(contents)) => format!("{:x}", Sha256::digest(contents.as_bytes())),
+                (None, None) => unreachable!("file task has neither src nor contents"),
+            };
+            let mut remote = Self::remote_file(worker, &target)?;
+            if remote == RemoteFile::Directory {
+                // like `cp`, a file is copied into an existing directory
+                let name = src
+                    .as_ref()
+                    .and_then(|s| s.file_name())
+                    .ok_or_else(|| Error::FileDstDirectory(dst.to_owned()))?;
+                target = dst.join(name);
+                remote = Self::remote_file(worker, &target)?;
+            }
+            if remote == RemoteFile::Exists(None) {
+                warn!(
+                    "No `sha256sum` on worker `{}`, upload `{}` without checking",
+                    worker.name(),
+                    target.display()
+                );
+            }
+
+            if !remote.upload_needed(&local_sha256) {
+                debug!("File `{}` on worker `{}` is up to date", target.display(), worker.name());
+                changed = false;
+            } else {
+                if self.backup && remote != RemoteFile::Absent {
+                    Self::backup(worker, &target)?;
+                }
+                if let Some(contents) = contents {
+                    let target_quoted = quote(target.to_string_lossy())?;
+                    let mut cmd_params = CmdParams::quiet();
+                    cmd_params.stdin = Some(contents);
+                    worker.shell(format!("cat > {target_quoted}"), &cmd_params)?;
+                } else if let Some(src) = src {
+                    worker.copy(src, &target)?;
+                }
+            }
+        }
+
+        let dst_str = dst.to_string_lossy().to_string();
+        let target_str = target.to_string_lossy().to_string();
+        if (self.chown.is_some() || self.chmod.is_some())
+            && (changed || self.attrs_differ(worker, &target)?)
+        {
+            if let Some(chown) = &self.chown {
+                let mut args = vec!["chown"];
+                if recursive {
+                    args.push("-R");
+                }
+                args.extend([chown.as_str(), &target_str]);
+                worker.exec(&args, &CmdParams::quiet())?;
+            }
+
+            if let Some(chmod) = &self.chmod {
+                let mut args = vec!["chmod"];
+                if recursive {
+                    args.push("-R");
+                }
+                args.extend([chmod.as_str(), &target_str]);
+                worker.exec(&args, &CmdParams::quiet())?;
+            }
+            changed = true;
+        }
+
+        let mut result: TaskResult = Value::String(dst_str).into();
+        result.set_register(serde_json::json!({ "dst": target_str, "changed": changed }));
+
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct GatherFactsType {}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum GetTypeSrc {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct GetType {
     #[serde(alias = "source")]
-    pub src: PathBuf,
+    src: GetTypeSrc,
     #[serde(alias = "dest")]
     #[serde(alias = "destination")]
-    pub dst: Option<PathBuf>,
+    dst: Option<PathBuf>,
+    dst_per_worker: Option<bool>,
+    #[serde(default)]
+    checksum: bool,
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Shell command printing paths matched by the glob. The directory part of the
+/// pattern is quoted, only the part starting from the first glob component is
+/// left for the shell to expand.
+fn glob_cmd(pattern: &Path) -> Result<String> {
+    let mut dir = PathBuf::new();
+    let mut glob = PathBuf::new();
+    for component in pattern.components() {
+        if glob.as_os_str().is_empty() && !is_glob(component.as_ref()) {
+            dir.push(component);
+        } else {
+            glob.push(component);
+        }
+    }
+    if glob.as_os_str().is_empty() {
+        bail!(Error::GetSrcNoMatch(pattern.to_owned()));
+    }
+
+    let pattern = if dir.as_os_str().is_empty() {
+        glob.display().to_string()
+    } else {
+        let dir = dir.to_string_lossy();
+        format!("{}/{}", shlex::try_quote(&dir)?.trim_end_matches('/'), glob.display())
+    };
+
+    Ok(format!("for f in {pattern}; do [ -e \"$f\" ] && printf '%s\\n' \"$f\"; done; true"))
+}
+
+impl GetType {
+    fn expand_glob(worker: &Worker, pattern: &Path) -> Result<Vec<PathBuf>> {
+        let out = worker.shell(glob_cmd(pattern)?, &CmdParams::quiet())?;
+        let paths = out.stdout().lines().map(PathBuf::from).collect::<Vec<_>>();
+        if paths.is_empty() {
+            bail!(Error::GetSrcNoMatch(pattern.to_owned()));
+        }
+
+        Ok(paths)
+    }
+
+    /// Destination of a single source path, the file name of `src` in the
+    /// manifest directory by default.
+    fn single_dst(src: &Path, dst: Option<PathBuf>, manifest_dir: &Path) -> Result<PathBuf> {
+        match dst {
+            Some(dst) => Ok(dst),
+            None => {
+                let name = src.file_name().ok_or_else(|| Error::GetSrcFilename(src.to_owned()))?;
+                Ok(manifest_dir.join(name))
+            }
+        }
+    }
+
+    /// Directory to store several source paths relative to the manifest
+    /// directory, with a subdirectory of the worker if it is set.
+    fn dst_dir(dst: Option<PathBuf>, manifest_dir: &Path, worker_name: Option<&str>) -> PathBuf {
+        let dir = manifest_dir.join(dst.unwrap_or_default());
+        match worker_name {
+            Some(worker_name) => dir.join(worker_name),
+            None => dir,
+        }
+    }
+
+    fn verify(worker: &Worker, src: &Path, dst: &Path) -> Result<()> {
+        let (remote, local) = if dst.is_dir() {
+            let mut local = BTreeMap::new();
+            for file in walk_files(dst)? {
+                let name = file.strip_prefix(dst)?.to_string_lossy().to_string();
+                local.insert(name, sha256_file(&file)?);
+            }
+            let remote = FileType::remote_sha256_dir(worker, src)?
+                .into_iter()
+                .map(|(name, hash)| (name, hash.unwrap_or(NO_SHA256SUM.to_string())))
+                .collect::<BTreeMap<_, _>>();
+            (serde_json::to_string(&remote)?, serde_json::to_string(&local)?)
+        } else {
+            let remote = match FileType::remote_file(worker, src)? {
+                RemoteFile::Exists(Some(sha256)) => sha256,
+                RemoteFile::Exists(None) => NO_SHA256SUM.to_string(),
+                RemoteFile::Absent | RemoteFile::Directory => String::new(),
+            };
+            (remote, sha256_file(dst)?)
+        };
+        if remote != local {
+            let error = Error::GetChecksumMismatch(src.to_owned());
+            return error.result([("remote", remote.as_str()), ("local", local.as_str())]);
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<Value> {
+        // several workers of the task would overwrite the same files
+        let per_worker = self.dst_per_worker.unwrap_or(env.task_workers > 1);
+        let (srcs, single) = match &self.src {
+            GetTypeSrc::One(src) => (vec![src.to_owned()], !is_glob(src) && !per_worker),
+            GetTypeSrc::Many(srcs) => (srcs.to_owned(), false),
+        };
+        let srcs = srcs.render(context, "get task src")?;
+        let dst = self.dst.render(context, "get task dst")?;
+
+        let mut pairs = vec![];
+        if single {
+            let src = &srcs[0];
+            pairs.push((src.to_owned(), Self::single_dst(src, dst, env.dir)?));
+        } else {
+            let worker_name = worker.name();
+            let dir = Self::dst_dir(dst, env.dir, per_worker.then_some(worker_name.as_str()));
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create directory `{}`", dir.display()))?;
+            for src in srcs {
+                let srcs =
+                    if is_glob(&src) { Self::expand_glob(worker, &src)? } else { vec![src] };
+                for src in srcs {
+                    let name =
+                        src.file_name().ok_or_else(|| Error::GetSrcFilename(src.to_owned()))?;
+                    let dst = dir.join(name);
+                    pairs.push((src, dst));
+                }
+            }
+        }
+
+        let mut dsts = vec![];
+        for (src, dst) in pairs {
+            worker.get(&src, &dst)?;
+            if self.checksum {
+                Self::verify(worker, &src, &dst)?;
+            }
+            dsts.push(Value::String(dst.to_string_lossy().to_string()));
+        }
+
+        if single {
+            Ok(dsts.remove(0))
+        } else {
+            Ok(Value::Array(dsts))
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -157,6 +1061,104 @@ pub struct InfoType {
     pub result: Option<Value>,
 }
 
+/// Render a template file or a directory tree of templates to a temporary
+/// location and return the path of the rendered copy.
+fn render_template_path(template: &Path, context: &Context) -> Result<PathBuf> {
+    let place = "file task template";
+    let name = template.file_name().ok_or_else(|| Error::TemplateFilename(template.to_owned()))?;
+    let rendered = mktemp_dir()?.join(name);
+    if template.is_dir() {
+        let renderer = FileRenderer::from_dir(template)?;
+        for name in renderer.names() {
+            let path = rendered.join(&name);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, renderer.render(context, template.join(&name), place)?)?;
+        }
+    } else {
+        let renderer = FileRenderer::from_file(template)?;
+        fs::write(&rendered, renderer.render(context, template, place)?)?;
+    }
+
+    Ok(rendered)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct LineInFileType {
+    #[serde(alias = "dest")]
+    path: PathBuf,
+    line: Option<String>,
+    #[serde(alias = "re")]
+    regexp: Option<String>,
+    #[serde(default)]
+    state: EditFileState,
+    insert_after: Option<String>,
+    insert_before: Option<String>,
+    #[serde(default)]
+    create: bool,
+}
+
+impl LineInFileType {
+    fn edit(&self, content: &str) -> Result<String> {
+        let mut lines = content.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+        let re = self.regexp.as_ref().map(|r| Regex::new(r)).transpose()?;
+        let is_target = |l: &str| match &re {
+            Some(re) => re.is_match(l),
+            None => Some(l) == self.line.as_deref(),
+        };
+
+        match self.state {
+            EditFileState::Present => {
+                let line = self.line.as_ref().ok_or(Error::NoArgument("line".to_string()))?;
+                if let Some(index) = lines.iter().rposition(|l| is_target(l)) {
+                    line.clone_into(&mut lines[index]);
+                } else if !lines.contains(line) {
+                    let index =
+                        edit_file_insert_index(&lines, &self.insert_after, &self.insert_before)?;
+                    lines.insert(index, line.to_string());
+                }
+            }
+            EditFileState::Absent => {
+                if self.line.is_none() && re.is_none() {
+                    bail!(Error::NoArgument("line or regexp".to_string()));
+                }
+                lines.retain(|l| !is_target(l));
+            }
+        }
+
+        Ok(edit_file_join(&lines, content))
+    }
+
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<Value> {
+        let line_in_file = self.render(context, "line-in-file task")?;
+        let content = edit_file_read(worker, &line_in_file.path, self.create)?;
+        let new_content = line_in_file.edit(&content)?;
+        let changed = new_content != content;
+        if changed {
+            edit_file_write(worker, &line_in_file.path, new_content)?;
+        }
+
+        Ok(Value::Bool(changed))
+    }
+}
+
+impl Render for LineInFileType {
+    fn render<S: AsRef<str>>(&self, context: &Context, place: S) -> Result<Self> {
+        let place = place.as_ref();
+        let path = self.path.render(context, format!("path in {}", place))?;
+        let line = self.line.render(context, format!("line in {}", place))?;
+        let regexp = self.regexp.render(context, format!("regexp in {}", place))?;
+        let insert_after =
+            self.insert_after.render(context, format!("insert-after in {}", place))?;
+        let insert_before =
+            self.insert_before.render(context, format!("insert-before in {}", place))?;
+        Ok(Self { path, line, regexp, insert_after, insert_before, ..self.to_owned() })
+    }
+}
+
 fn default_cmd_output_log() -> LevelFilter {
     LevelFilter::Off
 }
@@ -195,6 +1197,51 @@ enum CmdParamsResultStream {
     Stderr,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CmdParamsResultFormat {
+    Csv,
+    Json,
+    Kv,
+    Toml,
+    Yaml,
+}
+
+impl CmdParamsResultFormat {
+    fn parse(&self, output: &str) -> Result<Value> {
+        let value = match self {
+            Self::Csv => {
+                let mut rows = vec![];
+                let mut rdr = csv::Reader::from_reader(output.as_bytes());
+                for row in rdr.deserialize() {
+                    let row: BTreeMap<String, String> = row?;
+                    rows.push(serde_json::to_value(row)?);
+                }
+                Value::Array(rows)
+            }
+            Self::Json => serde_json::from_str(output)?,
+            Self::Kv => {
+                let mut object = serde_json::Map::new();
+                for (number, line) in output.lines().map(|l| l.trim()).enumerate() {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let Some((key, value)) = line.split_once('=') else {
+                        return Error::ResultKvNoDelimiter(line.to_string())
+                            .result([("line_number", number + 1)]);
+                    };
+                    object.insert(key.trim().to_string(), Value::String(value.trim().to_string()));
+                }
+                Value::Object(object)
+            }
+            Self::Toml => toml::from_str(output)?,
+            Self::Yaml => serde_yaml::from_str(output)?,
+        };
+
+        Ok(value)
+    }
+}
+
 fn default_cmd_params_result_lines() -> bool {
     true
 }
@@ -207,10 +1254,14 @@ fn default_cmd_params_result_strip() -> bool {
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
 pub struct CmdParamsResult {
+    #[serde(default)]
+    all: bool,
+    format: Option<CmdParamsResultFormat>,
     #[serde(default = "default_cmd_params_result_lines")]
     lines: bool,
     #[serde(default)]
     matched: bool,
+    regex: Option<String>,
     #[serde(default)]
     #[serde(alias = "rc")]
     return_code: bool,
@@ -221,13 +1272,22 @@ pub struct CmdParamsResult {
 }
 
 impl CmdParamsResult {
-    fn get(&self, out: CmdOut) -> Value {
+    fn get(&self, out: CmdOut) -> Result<Value> {
         if self.return_code {
-            return out.rc().map(|c| c.into()).unwrap_or(Value::Null);
+            return Ok(out.rc().map(|c| c.into()).unwrap_or(Value::Null));
         }
 
         if self.matched {
-            return Value::Bool(out.matched);
+            return Ok(Value::Bool(out.matched));
+        }
+
+        if self.all {
+            return Ok(serde_json::json!({
+                "stdout": out.stdout(),
+                "stderr": out.stderr(),
+                "rc": out.rc(),
+                "matched": out.matched,
+            }));
         }
 
         let mut result = match self.stream {
@@ -239,20 +1299,59 @@ impl CmdParamsResult {
             result = result.trim_end().to_string();
         }
 
+        if let Some(format) = &self.format {
+            return match format.parse(&result) {
+                Ok(value) => Ok(value),
+                Err(error) => {
+                    let format = serde_json::to_value(format)?;
+                    let format = format.as_str().unwrap_or_default().to_string();
+                    Error::ResultParse(format)
+                        .result([("error", format!("{error:#}")), ("output", result)])
+                }
+            };
+        }
+
+        if let Some(regex) = &self.regex {
+            let re = Regex::new(regex)?;
+            let Some(captures) = re.captures(&result) else {
+                return Error::ResultRegexNoMatch(regex.to_string()).result([("output", result)]);
+            };
+            let mut object = serde_json::Map::new();
+            for name in re.capture_names().flatten() {
+                let value = captures.name(name).map(|m| Value::String(m.as_str().to_string()));
+                object.insert(name.to_string(), value.unwrap_or(Value::Null));
+            }
+            return Ok(Value::Object(object));
+        }
+
         if self.lines {
             let a = result.lines().map(|l| Value::String(l.to_string())).collect::<Vec<_>>();
-            Value::Array(a)
+            Ok(Value::Array(a))
         } else {
-            Value::String(result)
+            Ok(Value::String(result))
         }
     }
 }
 
+/// Registered value of a command, which is assumed to change the worker.
+fn cmd_register(out: &CmdOut) -> Value {
+    serde_json::json!({
+        "stdout": out.stdout(),
+        "stderr": out.stderr(),
+        "rc": out.rc(),
+        "matched": out.matched,
+        "changed": true,
+    })
+}
+
 impl Default for CmdParamsResult {
     fn default() -> Self {
         Self {
+            all: Default::default(),
+            format: Default::default(),
             lines: default_cmd_params_result_lines(),
             matched: Default::default(),
+            regex: Default::default(),
             return_code: Default::default(),
             stream: Default::default(),
             strip: default_cmd_params_result_strip(),
@@ -352,9 +1451,12 @@ impl ExecType {
         worker.exec(&self.args.render(context, "args in exec task")?, &params)
     }
 
-    pub fn run(&self, context: &Context, worker: &Worker) -> Result<Value> {
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<TaskResult> {
         let out = self.run_out(context, worker, default_cmd_check())?;
-        Ok(self.params.result.get(out))
+        let register = cmd_register(&out);
+        let mut result: TaskResult = self.params.result.get(out)?.into();
+        result.set_register(register);
+        Ok(result)
     }
 }
 
@@ -362,6 +1464,14 @@ fn default_run_lineup_clean() -> bool {
     true
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReturnType {
+    #[serde(default)]
+    pub result: Option<Value>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RunLineupType {
@@ -399,6 +1509,41 @@ pub struct RunTasksetType {
     worker: RunTasksetTypeWorker,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SetScope {
+    #[default]
+    Taskline,
+    Parent,
+    Worker,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct SetType {
+    vars: ExtVars,
+    #[serde(default)]
+    scope: SetScope,
+}
+
+impl SetType {
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<TaskResult> {
+        let vars = self.vars.render(context, "set task")?.vars()?.context()?.into_json();
+        let mut result: TaskResult = vars.to_owned().into();
+        match self.scope {
+            SetScope::Taskline => result.add_vars(vars),
+            SetScope::Parent => result.add_parent_vars(vars),
+            SetScope::Worker => {
+                worker.set_vars(Context::from_value(vars.to_owned())?);
+                result.add_vars(vars);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
@@ -416,9 +1561,12 @@ impl ShellType {
         worker.shell(self.command.render(context, "command in shell task")?, &params)
     }
 
-    pub fn run(&self, context: &Context, worker: &Worker) -> Result<Value> {
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<TaskResult> {
         let out = self.run_out(context, worker, default_cmd_check())?;
-        Ok(self.params.result.get(out))
+        let register = cmd_register(&out);
+        let mut result: TaskResult = self.params.result.get(out)?.into();
+        result.set_register(register);
+        Ok(result)
     }
 }
 
@@ -461,32 +1609,395 @@ impl TestTypeCommand {
                 let exec = ExecType { args: args.to_owned(), params: Default::default() };
                 exec.run_out(context, worker, check)
             }
-            Self::Shell(shell) => shell.run_out(context, worker, check),
-            Self::ShellCommand(command) => {
-                let shell = ShellType { command: command.to_string(), params: Default::default() };
-                shell.run_out(context, worker, check)
+            Self::Shell(shell) => shell.run_out(context, worker, check),
+            Self::ShellCommand(command) => {
+                let shell = ShellType { command: command.to_string(), params: Default::default() };
+                shell.run_out(context, worker, check)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct TestType {
+    #[serde(alias = "cmds")]
+    commands: Vec<TestTypeCommand>,
+    #[serde(default = "default_cmd_check")]
+    check: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct TraceType {
+    msg: String,
+    #[serde(default)]
+    pub result: Option<Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct UnarchiveType {
+    #[serde(alias = "source")]
+    src: PathBuf,
+    #[serde(alias = "dest")]
+    #[serde(alias = "destination")]
+    dst: PathBuf,
+    format: Option<ArchiveFormat>,
+    #[serde(default)]
+    remote: bool,
+    strip_components: Option<u32>,
+    creates: Option<PathBuf>,
+}
+
+impl UnarchiveType {
+    pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<Value> {
+        let src = self.src.render(context, "src in unarchive task")?;
+        let dst = self.dst.render(context, "dst in unarchive task")?;
+
+        if let Some(creates) = self.creates.render(context, "creates in unarchive task")? {
+            let mut params = CmdParams::quiet();
+            params.check = Some(false);
+            if worker.exec(&["test", "-e", &creates.to_string_lossy()], &params)?.success() {
+                debug!(
+                    "Skip unarchive, `{}` exists on worker `{}`",
+                    creates.display(),
+                    worker.name()
+                );
+                return Ok(Value::Bool(false));
+            }
+        }
+
+        let format = match &self.format {
+            Some(format) => format.to_owned(),
+            None => ArchiveFormat::from_path(&src)?,
+        };
+        let upload = if self.remote {
+            None
+        } else {
+            let workdir = worker.workdir().to_string_lossy().to_string();
+            worker.exec(&["mkdir", "-p", &workdir], &CmdParams::quiet())?;
+            let out = worker
+                .exec(&["mktemp", "-p", &workdir, "unarchive.XXXXXXXX"], &CmdParams::quiet())?;
+            Some(PathBuf::from(out.stdout().trim()))
+        };
+        let archive = upload.clone().unwrap_or_else(|| src.to_owned());
+
+        let extract = || -> Result<()> {
+            if let Some(upload) = &upload {
+                worker.copy(env.dir.join(&src), upload)?;
+            }
+            worker.exec(&["mkdir", "-p", &dst.to_string_lossy()], &CmdParams::quiet())?;
+            let mut args = vec!["tar".to_string(), "-x".to_string()];
+            args.extend(format.tar_args());
+            args.extend(["-f".to_string(), archive.to_string_lossy().to_string()]);
+            args.extend(["-C".to_string(), dst.to_string_lossy().to_string()]);
+            if let Some(strip_components) = self.strip_components {
+                args.push(format!("--strip-components={strip_components}"));
+            }
+            worker.exec(&args, &CmdParams::quiet())?;
+
+            Ok(())
+        };
+        let out = extract();
+
+        if let Some(upload) = &upload {
+            worker.exec(&["rm", "-f", "--", &upload.to_string_lossy()], &CmdParams::quiet())?;
+        }
+        out?;
+
+        Ok(Value::Bool(true))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitForPort {
+    #[serde(default = "default_wait_for_port_host")]
+    host: String,
+    port: StringOrInt,
+    #[serde(default)]
+    on_host: bool,
+}
+
+fn default_wait_for_port_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitForFile {
+    path: PathBuf,
+    #[serde(default)]
+    state: EditFileState,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitForCommand {
+    #[serde(alias = "cmd")]
+    command: String,
+    matches: Option<Matches>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitForLog {
+    path: PathBuf,
+    #[serde(alias = "re")]
+    regexp: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WaitForCondition {
+    Port(WaitForPort),
+    File(WaitForFile),
+    Command(WaitForCommand),
+    Log(WaitForLog),
+}
+
+struct WaitForProbe {
+    ok: bool,
+    stdout: String,
+    stderr: String,
+}
+
+impl WaitForProbe {
+    fn from_out(ok: bool, out: &CmdOut) -> Self {
+        Self { ok, stdout: out.stdout(), stderr: out.stderr() }
+    }
+}
+
+impl WaitForCondition {
+    fn describe(&self) -> String {
+        match self {
+            Self::Port(port) => format!("port `{}:{}`", port.host, port.port),
+            Self::File(file) => format!("file `{}`", file.path.display()),
+            Self::Command(command) => format!("command `{}`", command.command),
+            Self::Log(log) => format!("`{}` in log `{}`", log.regexp, log.path.display()),
+        }
+    }
+
+    fn probe(&self, worker: &Worker) -> Result<WaitForProbe> {
+        let mut params = CmdParams::quiet();
+        params.check = Some(false);
+        match self {
+            Self::Port(WaitForPort { host, port, on_host: true }) => {
+                let addrs = match format!("{host}:{port}").to_socket_addrs() {
+                    Ok(addrs) => addrs.collect::<Vec<_>>(),
+                    Err(error) => {
+                        let stderr = error.to_string();
+                        return Ok(WaitForProbe { ok: false, stdout: "".to_string(), stderr });
+                    }
+                };
+                let mut stderr = "".to_string();
+                for addr in addrs {
+                    match TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+                        Ok(_) => {
+                            return Ok(WaitForProbe { ok: true, stdout: addr.to_string(), stderr })
+                        }
+                        Err(error) => stderr = error.to_string(),
+                    }
+                }
+                Ok(WaitForProbe { ok: false, stdout: "".to_string(), stderr })
+            }
+            Self::Port(WaitForPort { host, port, on_host: false }) => {
+                let host = quote(host)?;
+                let port = quote(port.to_string())?;
+                let cmd = format!(
+                    "if command -v nc >/dev/null 2>&1; then nc -z -w 1 {host} {port}; \
+                     else timeout 1 bash -c \"exec 3<>/dev/tcp/{host}/{port}\"; fi"
+                );
+                let out = worker.shell(cmd, &params)?;
+                Ok(WaitForProbe::from_out(out.success(), &out))
+            }
+            Self::File(WaitForFile { path, state }) => {
+                let out = worker.exec(&["test", "-e", &path.to_string_lossy()], &params)?;
+                let ok = match state {
+                    EditFileState::Present => out.success(),
+                    EditFileState::Absent => !out.success(),
+                };
+                Ok(WaitForProbe::from_out(ok, &out))
+            }
+            Self::Command(WaitForCommand { command, matches }) => {
+                let out = worker.shell(command, &params)?;
+                let ok = match matches {
+                    Some(matches) if matches.has_rc() => {
+                        matches.is_match(out.stdout(), out.stderr(), out.rc())?
+                    }
+                    Some(matches) => {
+                        out.success() && matches.is_match(out.stdout(), out.stderr(), out.rc())?
+                    }
+                    None => out.success(),
+                };
+                Ok(WaitForProbe::from_out(ok, &out))
+            }
+            Self::Log(WaitForLog { path, regexp }) => {
+                let out = worker.exec(&["cat", "--", &path.to_string_lossy()], &params)?;
+                let re = Regex::new(regexp)?;
+                let stdout = out.stdout();
+                let line = stdout.lines().find(|line| re.is_match(line));
+                Ok(WaitForProbe {
+                    ok: line.is_some(),
+                    stdout: line.unwrap_or_default().to_string(),
+                    stderr: out.stderr(),
+                })
             }
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-#[serde(rename_all = "kebab-case")]
-pub struct TestType {
-    #[serde(alias = "cmds")]
-    commands: Vec<TestTypeCommand>,
-    #[serde(default = "default_cmd_check")]
-    check: bool,
+impl Render for WaitForCondition {
+    fn render<S: AsRef<str>>(&self, context: &Context, place: S) -> Result<Self> {
+        let place = place.as_ref();
+        Ok(match self {
+            Self::Port(port) => Self::Port(WaitForPort {
+                host: port.host.render(context, format!("host in port in {}", place))?,
+                port: port.port.render(context, format!("port in port in {}", place))?,
+                on_host: port.on_host,
+            }),
+            Self::File(file) => Self::File(WaitForFile {
+                path: file.path.render(context, format!("path in file in {}", place))?,
+                state: file.state.to_owned(),
+            }),
+            Self::Command(command) => Self::Command(WaitForCommand {
+                command: command
+                    .command
+                    .render(context, format!("command in command in {}", place))?,
+                matches: command
+                    .matches
+                    .render(context, format!("matches in command in {}", place))?,
+            }),
+            Self::Log(log) => Self::Log(WaitForLog {
+                path: log.path.render(context, format!("path in log in {}", place))?,
+                regexp: log.regexp.render(context, format!("regexp in log in {}", place))?,
+            }),
+        })
+    }
+}
+
+fn default_wait_for_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_wait_for_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_wait_for_backoff() -> f64 {
+    1.0
 }
 
+#[serde_with::serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
-pub struct TraceType {
-    msg: String,
+pub struct WaitForType {
+    #[serde(flatten)]
+    condition: WaitForCondition,
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>")]
+    #[serde(default = "default_wait_for_timeout")]
+    timeout: Duration,
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>")]
+    #[serde(default = "default_wait_for_interval")]
+    interval: Duration,
+    #[serde(default = "default_wait_for_backoff")]
+    backoff: f64,
+    #[serde_as(
+        as = "Option<serde_with::DurationSecondsWithFrac<f64, serde_with::formats::Flexible>>"
+    )]
     #[serde(default)]
-    pub result: Option<Value>,
+    max_interval: Option<Duration>,
+}
+
+/// Upper limit of a delay growing with a backoff factor.
+const MAX_BACKOFF_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn check_backoff_factor(factor: f64) -> Result<()> {
+    if !factor.is_finite() || factor < 1.0 {
+        bail!(Error::BadBackoffFactor(factor));
+    }
+
+    Ok(())
+}
+
+/// `delay * factor^exponent` limited by `max` and `MAX_BACKOFF_DELAY`.
+pub fn backoff_delay(
+    delay: Duration,
+    factor: f64,
+    exponent: u32,
+    max: Option<Duration>,
+) -> Duration {
+    let max = max.unwrap_or(MAX_BACKOFF_DELAY).min(MAX_BACKOFF_DELAY);
+    let factor = factor.powi(exponent.min(i32::MAX as u32) as i32);
+
+    Duration::try_from_secs_f64(delay.as_secs_f64() * factor).unwrap_or(max).min(max)
+}
+
+impl WaitForType {
+    pub fn run(&self, context: &Context, worker: &Worker) -> Result<Value> {
+        let condition = self.condition.render(context, "wait-for task")?;
+        if let WaitForCondition::Log(log) = &condition {
+            Regex::new(&log.regexp)?;
+        }
+        let start = Instant::now();
+
+        self.poll(
+            &condition.describe(),
+            || condition.probe(worker),
+            thread::sleep,
+            || start.elapsed(),
+        )
+    }
+
+    /// Probe until success or the timeout, the last probe is at the deadline.
+    /// Errors of probes (e.g. a worker is not reachable yet) are retried.
+    fn poll(
+        &self,
+        describe: &str,
+        mut probe: impl FnMut() -> Result<WaitForProbe>,
+        mut sleep: impl FnMut(Duration),
+        elapsed: impl Fn() -> Duration,
+    ) -> Result<Value> {
+        check_backoff_factor(self.backoff)?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let probe = probe().unwrap_or_else(|error| {
+                debug!("Wait for {} attempt={} error: {:#}", describe, attempts, error);
+                WaitForProbe { ok: false, stdout: "".to_string(), stderr: format!("{error:#}") }
+            });
+            let elapsed = elapsed();
+            trace!("Wait for {} attempt={} ok={}", describe, attempts, probe.ok);
+            if probe.ok {
+                return Ok(serde_json::json!({
+                    "elapsed": elapsed.as_secs_f64(),
+                    "attempts": attempts,
+                    "stdout": probe.stdout,
+                    "stderr": probe.stderr,
+                }));
+            }
+            if elapsed >= self.timeout {
+                let error =
+                    Error::WaitForTimeout(describe.to_string(), self.timeout.as_secs_f64());
+                return error.result([
+                    ("attempts", attempts.to_string()),
+                    ("stdout", probe.stdout.trim_end().to_string()),
+                    ("stderr", probe.stderr.trim_end().to_string()),
+                ]);
+            }
+            let interval =
+                backoff_delay(self.interval, self.backoff, attempts - 1, self.max_interval);
+            sleep(interval.min(self.timeout - elapsed));
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -501,23 +2012,34 @@ pub struct WarnType {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum TaskType {
+    Archive(ArchiveType),
+    Assert(AssertType),
+    Block(BlockType),
+    BlockInFile(BlockInFileType),
     Break(BreakType),
+    Continue(ContinueType),
     Debug(DebugType),
     Dummy(DummyType),
     Ensure(EnsureType),
     Error(ErrorType),
     Exec(ExecType),
     File(FileType),
+    GatherFacts(GatherFactsType),
     Get(GetType),
     Info(InfoType),
+    LineInFile(LineInFileType),
+    Return(ReturnType),
     RunLineup(RunLineupType),
     Run(String),
     RunTaskline(RunTasklineType),
     RunTaskset(RunTasksetType),
+    Set(SetType),
     Shell(ShellType),
     Special(SpecialType),
     Test(TestType),
     Trace(TraceType),
+    Unarchive(UnarchiveType),
+    WaitFor(WaitForType),
     Warn(WarnType),
 }
 
@@ -525,6 +2047,12 @@ impl TaskType {
     pub fn run(&self, context: &Context, env: &Env, worker: &Worker) -> Result<TaskResult> {
         let mut context = context.to_owned();
         match self {
+            Self::Archive(archive) => archive.run(&context, env, worker).map(|ok| ok.into()),
+            Self::Assert(assert) => assert.run(&context).map(|ok| ok.into()),
+            Self::Block(block) => block.run(&context, env, worker),
+            Self::BlockInFile(block_in_file) => {
+                block_in_file.run(&context, worker).map(|ok| ok.into())
+            }
             Self::Break(BreakType { taskline, result }) => {
                 let result = if let Some(result) = result {
                     result.render(&context, "break result")?
@@ -537,6 +2065,14 @@ impl TaskType {
                 }
                 .into())
             }
+            Self::Continue(ContinueType { result }) => {
+                let result = if let Some(result) = result {
+                    result.render(&context, "continue result")?
+                } else {
+                    context.get("result").cloned().unwrap_or(Value::Null)
+                };
+                Ok(Exception::Continue { result }.into())
+            }
             Self::Debug(DebugType { msg, result }) => {
                 let msg = msg.render(&context, "debug msg")?;
                 debug!("{}", msg);
@@ -558,52 +2094,15 @@ impl TaskType {
                 let msg = msg.render(&context, "error msg")?;
                 bail!(Error::User(msg, *code, *trace));
             }
-            Self::Exec(exec) => exec.run(&context, worker).map(|ok| ok.into()),
-            Self::File(FileType { dst, source, chown, chmod }) => {
-                let dst = dst.render(&context, "file task dst")?;
-                match source {
-                    FileTypeSource::Src(src) => {
-                        worker.copy(src.render(&context, "file task src")?, &dst)
-                    }
-                    FileTypeSource::Content(contents) => {
-                        let contents = contents.render(&context, "file task contents")?;
-                        let dst_quoted = quote(dst.to_string_lossy())?;
-                        let mut cmd_params = CmdParams::quiet();
-                        cmd_params.stdin = Some(contents);
-                        worker.shell(format!("cat > {dst_quoted}"), &cmd_params)?;
-                        Ok(())
-                    }
-                }?;
-
-                if let Some(chown) = chown {
-                    worker.exec(
-                        &["chown", "-R", chown, &dst.to_string_lossy()],
-                        &CmdParams::quiet(),
-                    )?;
-                }
-
-                if let Some(chmod) = chmod {
-                    worker.exec(
-                        &["chmod", "-R", chmod, &dst.to_string_lossy()],
-                        &CmdParams::quiet(),
-                    )?;
-                }
-
-                Ok(Value::String(dst.to_string_lossy().to_string()).into())
-            }
-            Self::Get(GetType { src, dst }) => {
-                let src = src.render(&context, "get task src")?;
-                let dst = if let Some(dst) = dst {
-                    dst.render(&context, "get task dst")?
-                } else {
-                    let name =
-                        src.file_name().ok_or_else(|| Error::GetSrcFilename(src.to_owned()))?;
-                    env.dir.join(name)
-                };
-                worker.get(src, &dst)?;
-
-                Ok(Value::String(dst.to_string_lossy().to_string()).into())
+            Self::Exec(exec) => exec.run(&context, worker),
+            Self::File(file) => file.run(&context, env, worker),
+            Self::GatherFacts(_) => {
+                let facts = worker.gather_facts()?;
+                let mut result: TaskResult = facts.to_owned().into();
+                result.add_vars(serde_json::json!({ "facts": facts }));
+                Ok(result)
             }
+            Self::Get(get) => get.run(&context, env, worker).map(|ok| ok.into()),
             Self::Info(InfoType { msg, result }) => {
                 let msg = msg.render(&context, "info msg")?;
                 info!("{}", msg);
@@ -613,10 +2112,22 @@ impl TaskType {
                     Ok(context.get("result").cloned().unwrap_or(Value::Null).into())
                 }
             }
+            Self::LineInFile(line_in_file) => {
+                line_in_file.run(&context, worker).map(|ok| ok.into())
+            }
+            Self::Return(ReturnType { result }) => {
+                let result = if let Some(result) = result {
+                    result.render(&context, "return result")?
+                } else {
+                    context.get("result").cloned().unwrap_or(Value::Null)
+                };
+                Ok(Exception::Return { result }.into())
+            }
             Self::RunLineup(RunLineupType { manifest, exists, clean, vars }) => {
                 let manifest = manifest.render(&context, "run-lineup manifest")?;
                 let vars = vars.render(&context, "run-lineup vars")?;
                 context.extend(vars.context()?);
+                let _unbinding = unbind_worker();
                 let mut runner = Runner::from_manifest(manifest, &context)?;
                 runner.add_extra_vars(vars);
                 runner.set_worker_exists_action(exists.to_owned());
@@ -680,39 +2191,24 @@ impl TaskType {
                 env.tasklines = &new_tasklines;
                 env.dir = &dir;
 
-                let mut value = Value::Null;
-                for (iter, task) in taskline
-                    .as_line()
-                    .expect("get not line variant of taskline")
-                    .iter()
-                    .enumerate()
-                {
-                    let result =
-                        task.task.run(&task.name, &context, &env, worker).with_context(|| {
-                            format!("taskline: `{}`, number: `{}`", taskline_str, iter)
-                        })?;
-
-                    if let Some(v) = result.as_value() {
-                        if let Some(vars_context) = result.as_context() {
-                            context.extend(vars_context);
-                        }
-                        value = v.to_owned();
-                        context.insert("result", &value);
-                    } else if let Some(exception) = result.as_exception() {
-                        match exception {
-                            Exception::BreakTaskline { taskline, result } => {
-                                let break_taskline = taskline.as_ref().unwrap_or(&taskline_str);
-                                if break_taskline == &taskline_str {
-                                    return Ok(result.to_owned().into());
-                                } else {
-                                    return Ok(exception.to_owned().into());
-                                }
+                let line = taskline.as_line().expect("get not line variant of taskline");
+                let place = format!("taskline: `{}`", taskline_str);
+                let result =
+                    run_tasks(line, &place, &mut context, &env, worker)?.lift_parent_vars();
+                if let Some(exception) = result.as_exception() {
+                    match exception {
+                        Exception::BreakTaskline { taskline, result } => {
+                            let break_taskline = taskline.as_ref().unwrap_or(&taskline_str);
+                            if break_taskline == &taskline_str {
+                                return Ok(result.to_owned().into());
                             }
                         }
+                        Exception::Continue { .. } => {}
+                        Exception::Return { result } => return Ok(result.to_owned().into()),
                     }
                 }
 
-                Ok(value.into())
+                Ok(result)
             }
             Self::RunTaskset(RunTasksetType { module, worker }) => {
                 let module = module.render(&context, "run-taskline file")?;
@@ -743,6 +2239,7 @@ impl TaskType {
                     }
                 };
 
+                let _unbinding = unbind_worker();
                 let mut runner = Runner::from_manifest(file, &context)?;
                 runner.add_extra_vars(Vars::from(context.to_owned()));
                 runner.set_storages(env.storages);
@@ -750,19 +2247,28 @@ impl TaskType {
                 runner.run()?;
                 Ok(Value::Null.into())
             }
-            Self::Shell(shell) => shell.run(&context, worker).map(|ok| ok.into()),
+            Self::Set(set) => set.run(&context, worker),
+            Self::Shell(shell) => shell.run(&context, worker),
             Self::Special(SpecialType { type_, ignore_unsupported }) => {
                 worker.special(type_, *ignore_unsupported)?;
                 Ok(Value::Null.into())
             }
             Self::Test(TestType { commands, check }) => {
                 let mut success = true;
+                let mut registers = vec![];
 
                 for command in commands {
-                    success &= command.run(&context, worker, *check)?.success();
+                    let out = command.run(&context, worker, *check)?;
+                    success &= out.success();
+                    registers.push(cmd_register(&out));
                 }
 
-                Ok(Value::Bool(success).into())
+                let mut result: TaskResult = Value::Bool(success).into();
+                result.set_register(serde_json::json!({
+                    "success": success,
+                    "commands": registers,
+                }));
+                Ok(result)
             }
             Self::Trace(TraceType { msg, result }) => {
                 let msg = msg.render(&context, "trace msg")?;
@@ -773,6 +2279,8 @@ impl TaskType {
                     Ok(context.get("result").cloned().unwrap_or(Value::Null).into())
                 }
             }
+            Self::Unarchive(unarchive) => unarchive.run(&context, env, worker).map(|ok| ok.into()),
+            Self::WaitFor(wait_for) => wait_for.run(&context, worker).map(|ok| ok.into()),
             Self::Warn(WarnType { msg, result }) => {
                 let msg = msg.render(&context, "warn msg")?;
                 warn!("{}", msg);
@@ -806,29 +2314,25 @@ mod tests {
 
     #[test]
     fn empty_ensure_vars_empty_context() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = Default::default();
+        let ensure = EnsureType { vars: Default::default() };
         ensure.ensure_vars(&Context::new())
     }
 
     #[test]
     fn empty_ensure_vars() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = Default::default();
+        let ensure = EnsureType { vars: Default::default() };
         ensure.ensure_vars(&context())
     }
 
     #[test]
     fn non_nested_ensure_vars() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["user".parse()?, "packages".parse()?];
+        let ensure = EnsureType { vars: vec!["user".parse()?, "packages".parse()?] };
         ensure.ensure_vars(&context())
     }
 
     #[test]
     fn non_nested_ensure_vars_absent() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["target".parse()?];
+        let ensure = EnsureType { vars: vec!["target".parse()?] };
         assert!(ensure.ensure_vars(&context()).is_err());
 
         Ok(())
@@ -836,15 +2340,13 @@ mod tests {
 
     #[test]
     fn nested_ensure_vars() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["vars.one".parse()?, "out.in.one".parse()?];
+        let ensure = EnsureType { vars: vec!["vars.one".parse()?, "out.in.one".parse()?] };
         ensure.ensure_vars(&context())
     }
 
     #[test]
     fn nested_ensure_vars_absent() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["out.in.two".parse()?];
+        let ensure = EnsureType { vars: vec!["out.in.two".parse()?] };
         assert!(ensure.ensure_vars(&context()).is_err());
 
         Ok(())
@@ -852,17 +2354,575 @@ mod tests {
 
     #[test]
     fn top_level_ensure_vars() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["vars".parse()?, "out.in".parse()?];
+        let ensure = EnsureType { vars: vec!["vars".parse()?, "out.in".parse()?] };
         ensure.ensure_vars(&context())
     }
 
     #[test]
     fn top_level_ensure_vars_absent() -> Result<()> {
-        let mut ensure = EnsureType::default();
-        ensure.vars = vec!["out.vars".parse()?];
+        let ensure = EnsureType { vars: vec!["out.vars".parse()?] };
         assert!(ensure.ensure_vars(&context()).is_err());
 
         Ok(())
     }
+
+    fn line_in_file(toml: &str) -> LineInFileType {
+        toml::from_str(&format!("path = '/etc/config'\n{toml}")).unwrap()
+    }
+
+    fn block_in_file(toml: &str) -> BlockInFileType {
+        toml::from_str(&format!("path = '/etc/config'\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn line_in_file_append() -> Result<()> {
+        let edit = line_in_file("line = 'c'");
+        assert_eq!(edit.edit("a\nb\n")?, "a\nb\nc\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_present() -> Result<()> {
+        let edit = line_in_file("line = 'b'");
+        assert_eq!(edit.edit("a\nb\nc\n")?, "a\nb\nc\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_replace_regexp() -> Result<()> {
+        let edit = line_in_file("line = 'port = 22'\nregexp = '^#?port ='");
+        assert_eq!(edit.edit("a\n#port = 2222\nc\n")?, "a\nport = 22\nc\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_insert_after() -> Result<()> {
+        let edit = line_in_file("line = 'x'\ninsert-after = '^a'");
+        assert_eq!(edit.edit("a\nb\n")?, "a\nx\nb\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_insert_before_bof() -> Result<()> {
+        let edit = line_in_file("line = 'x'\ninsert-before = 'BOF'");
+        assert_eq!(edit.edit("a\nb\n")?, "x\na\nb\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_absent() -> Result<()> {
+        let edit = line_in_file("regexp = '^b'\nstate = 'absent'");
+        assert_eq!(edit.edit("a\nb1\nc\nb2\n")?, "a\nc\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_in_file_present_no_line() -> Result<()> {
+        let edit = line_in_file("regexp = '^b'");
+        assert!(edit.edit("a\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_in_file_insert() -> Result<()> {
+        let edit = block_in_file(r#"block = "x\ny""#);
+        assert_eq!(edit.edit("a\n")?, "a\n# BEGIN MANAGED BLOCK\nx\ny\n# END MANAGED BLOCK\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_in_file_replace() -> Result<()> {
+        let edit = block_in_file("block = 'z'");
+        let content = "a\n# BEGIN MANAGED BLOCK\nx\ny\n# END MANAGED BLOCK\nb\n";
+        assert_eq!(edit.edit(content)?, "a\n# BEGIN MANAGED BLOCK\nz\n# END MANAGED BLOCK\nb\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_in_file_absent() -> Result<()> {
+        let edit = block_in_file("state = 'absent'");
+        let content = "a\n# BEGIN MANAGED BLOCK\nx\n# END MANAGED BLOCK\nb\n";
+        assert_eq!(edit.edit(content)?, "a\nb\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn edit_file_line_endings() -> Result<()> {
+        let edit = line_in_file("line = 'c'");
+        assert_eq!(edit.edit("a\r\nb\r\n")?, "a\r\nb\r\nc\r\n");
+        assert_eq!(edit.edit("a\nb")?, "a\nb\nc");
+        assert_eq!(edit.edit("a\r\nb")?, "a\r\nb\r\nc");
+        assert_eq!(edit.edit("")?, "c\n");
+
+        let edit = line_in_file("line = 'b'");
+        assert_eq!(edit.edit("a\r\nb")?, "a\r\nb");
+
+        let edit = block_in_file("state = 'absent'");
+        let content = "a\r\n# BEGIN MANAGED BLOCK\r\nx\r\n# END MANAGED BLOCK\r\nb";
+        assert_eq!(edit.edit(content)?, "a\r\nb");
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_defaults() -> Result<()> {
+        let wait_for = toml::from_str::<WaitForType>("port = { port = 22 }")?;
+        assert!(matches!(wait_for.condition, WaitForCondition::Port(_)));
+        assert_eq!(wait_for.timeout, Duration::from_secs(60));
+        assert_eq!(wait_for.interval, Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_command() -> Result<()> {
+        let toml = "command = { cmd = 'true' }\ntimeout = 0.5\nbackoff = 2";
+        let wait_for = toml::from_str::<WaitForType>(toml)?;
+        assert!(matches!(wait_for.condition, WaitForCondition::Command(_)));
+        assert_eq!(wait_for.timeout, Duration::from_millis(500));
+        assert_eq!(wait_for.backoff, 2.0);
+
+        Ok(())
+    }
+
+    fn wait_for_poll(toml: &str, mut probes: Vec<Result<bool>>) -> (Result<Value>, Vec<f64>) {
+        let wait_for =
+            toml::from_str::<WaitForType>(&format!("file.path = '/tmp'\n{toml}")).unwrap();
+        let now = std::cell::Cell::new(Duration::ZERO);
+        let mut sleeps = vec![];
+        probes.reverse();
+        let result = wait_for.poll(
+            "test",
+            || {
+                let ok = probes.pop().expect("no more probes")?;
+                Ok(WaitForProbe { ok, stdout: "out".to_string(), stderr: "".to_string() })
+            },
+            |duration| {
+                sleeps.push(duration.as_secs_f64());
+                now.set(now.get() + duration);
+            },
+            || now.get(),
+        );
+
+        (result, sleeps)
+    }
+
+    #[test]
+    fn wait_for_poll_later_attempt() -> Result<()> {
+        let probes = vec![Ok(false), Err(anyhow::anyhow!("connection refused")), Ok(true)];
+        let (result, sleeps) = wait_for_poll("interval = 1\nbackoff = 2", probes);
+        let result = result?;
+        assert_eq!(result["attempts"], 3);
+        assert_eq!(result["elapsed"], 3.0);
+        assert_eq!(sleeps, [1.0, 2.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_poll_timeout() {
+        let probes = (0..4).map(|_| Ok(false)).collect();
+        let (result, sleeps) = wait_for_poll("timeout = 5\ninterval = 2", probes);
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::WaitForTimeout(_, _))));
+        assert!(format!("{error:#}").contains(r#"["attempts","4"]"#), "{error:#}");
+        // the last probe is at the deadline
+        assert_eq!(sleeps, [2.0, 2.0, 1.0]);
+
+        let (result, sleeps) = wait_for_poll("backoff = inf", vec![]);
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(Error::BadBackoffFactor(_))));
+        assert!(sleeps.is_empty());
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let second = Duration::from_secs(1);
+        assert_eq!(backoff_delay(second, 2.0, 3, None), Duration::from_secs(8));
+        assert_eq!(
+            backoff_delay(second, 2.0, 3, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(backoff_delay(second, 10.0, 1000, None), MAX_BACKOFF_DELAY);
+        assert_eq!(backoff_delay(second, 1.0, u32::MAX, None), second);
+        assert!(check_backoff_factor(0.5).is_err());
+        assert!(check_backoff_factor(f64::NAN).is_err());
+        assert!(check_backoff_factor(1.0).is_ok());
+    }
+
+    #[test]
+    fn block_sections() -> Result<()> {
+        let toml = "tasks = [{ error.msg = 'fail' }]\nalways = [{ info.msg = 'done' }]";
+        let block = toml::from_str::<BlockType>(toml)?;
+        assert_eq!(block.tasks.len(), 1);
+        assert!(block.rescue.is_empty());
+        assert_eq!(block.always.len(), 1);
+
+        Ok(())
+    }
+
+    fn run_task_type(toml: &str, context: &Context) -> Result<TaskResult> {
+        let task_type = toml::from_str::<TaskType>(toml)?;
+        let worker = Worker::test_host();
+        let (dir, storages, tasklines) = (PathBuf::new(), Default::default(), Default::default());
+        let env = Env {
+            dir: &dir,
+            storages: &storages,
+            tasklines: &tasklines,
+            workers: &vec![],
+            task_workers: 1,
+        };
+        task_type.run(context, &env, &worker)
+    }
+
+    #[test]
+    fn block_rescue_error_vars() -> Result<()> {
+        let toml = r#"block = { tasks = [{ error.msg = "boom" }], rescue = [
+            { dummy.result = "{{ error }} {{ error_context | json }}" }
+        ] }"#;
+        let result = run_task_type(toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&Value::String("boom {}".to_string())));
+
+        let toml =
+            "block = { tasks = [{ dummy.result = 'ok' }], rescue = [{ error.msg = 'no' }] }";
+        let result = run_task_type(toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&Value::String("ok".to_string())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_always() -> Result<()> {
+        let toml = "block = { tasks = [{ dummy.result = 1 }], always = [{ dummy.result = 2 }] }";
+        let result = run_task_type(toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&Value::from(2)));
+
+        let toml =
+            "block = { tasks = [{ dummy.result = 1 }], always = [{ error.msg = 'always' }] }";
+        let error = run_task_type(toml, &Context::new()).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "always");
+
+        // the error of tasks is kept if always fails too
+        let toml =
+            "block = { tasks = [{ error.msg = 'tasks' }], always = [{ error.msg = 'always' }] }";
+        let error = run_task_type(toml, &Context::new()).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "tasks");
+        assert!(matches!(error.downcast_ref(), Some(Error::User(..))));
+        assert!(format!("{error:#}").contains("block always failed"), "{error:#}");
+
+        let toml = "block = { tasks = [{ error.msg = 'tasks' }], rescue = [{ dummy = {} }], \
+                    always = [{ error.msg = 'always' }] }";
+        let error = run_task_type(toml, &Context::new()).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "always");
+
+        Ok(())
+    }
+
+    #[test]
+    fn assert_vars_skip_filters_and_strings() {
+        let mut context = Context::new();
+        context.insert("result", &vec!["a"]);
+        context.insert("rc", &1);
+        context.insert("length", &0);
+        let vars = assert_vars("result | length > 0 and rc == 0 and 'rc' != \"length\"", &context);
+        assert_eq!(
+            vars,
+            vec![
+                ("result".to_string(), "[\"a\"]".to_string()),
+                ("rc".to_string(), "1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn assert_that() -> Result<()> {
+        let mut context = Context::new();
+        context.insert("rc", &0);
+        let assert = toml::from_str::<AssertType>("that = ['rc == 0']\nquiet = true")?;
+        assert!(assert.run(&context).is_ok());
+        let assert = toml::from_str::<AssertType>("that = ['rc == 0', 'rc > 0']")?;
+        let error = assert.run(&context).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "assertion failed");
+        let context = r#"context_json: [["that","rc > 0"],["rc","0"]]"#;
+        assert_eq!(error.to_string(), context);
+
+        Ok(())
+    }
+
+    fn cmd_out(stdout: &str) -> CmdOut {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::{ExitStatus, Output};
+
+        let status = ExitStatus::from_raw(0);
+        CmdOut::new(Output { status, stdout: stdout.into(), stderr: vec![] })
+    }
+
+    #[test]
+    fn result_format_and_regex() -> Result<()> {
+        let result = toml::from_str::<CmdParamsResult>("format = 'kv'")?;
+        let value = result.get(cmd_out("# os\nID=alt\nVERSION = 10\n"))?;
+        assert_eq!(value, serde_json::json!({"ID": "alt", "VERSION": "10"}));
+        let error = result.get(cmd_out("ID=alt\nbroken\n")).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "failed to parse command output as kv");
+        assert!(error.to_string().contains("line `broken` of kv command output"));
+        assert!(error.to_string().contains(r#"[\"line_number\",\"2\"]"#));
+
+        let result = toml::from_str::<CmdParamsResult>("format = 'csv'")?;
+        let value = result.get(cmd_out("name,port\na,80\n"))?;
+        assert_eq!(value, serde_json::json!([{"name": "a", "port": "80"}]));
+
+        let result = toml::from_str::<CmdParamsResult>("format = 'json'")?;
+        let error = result.get(cmd_out("{oops")).unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "failed to parse command output as json");
+        assert!(error.to_string().contains("{oops"));
+
+        let result =
+            toml::from_str::<CmdParamsResult>(r#"regex = '(?P<ver>\d+\.\d+)(?P<rc>-rc)?'"#)?;
+        let value = result.get(cmd_out("lineup 1.42.0"))?;
+        assert_eq!(value, serde_json::json!({"ver": "1.42", "rc": null}));
+        assert!(result.get(cmd_out("lineup")).is_err());
+
+        let result = toml::from_str::<CmdParamsResult>("all = true")?;
+        let value = result.get(cmd_out("out"))?;
+        assert_eq!(value["rc"], 0);
+        assert_eq!(value["stdout"], "out");
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_dst_directory() -> Result<()> {
+        let dir = mktemp_dir()?;
+        fs::write(dir.join("a.conf"), "a")?;
+        fs::create_dir(dir.join("dstdir"))?;
+        let (src, dst) = (dir.join("a.conf"), dir.join("dstdir"));
+        let toml = format!("file = {{ src = {src:?}, dst = {dst:?} }}");
+
+        let result = run_task_type(&toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&Value::String(dst.to_string_lossy().to_string())));
+        let register = result.register_value(Duration::ZERO).unwrap();
+        assert_eq!(register["dst"], dst.join("a.conf").to_string_lossy().to_string());
+        assert_eq!(register["changed"], true);
+        assert_eq!(fs::read_to_string(dst.join("a.conf"))?, "a");
+
+        let result = run_task_type(&toml, &Context::new())?;
+        assert_eq!(result.register_value(Duration::ZERO).unwrap()["changed"], false);
+
+        let toml = format!("file = {{ content = 'a', dst = {dst:?} }}");
+        let error = run_task_type(&toml, &Context::new()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::FileDstDirectory(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_options_not_applicable() -> Result<()> {
+        let dir = mktemp_dir()?;
+        fs::write(dir.join("a.conf"), "a")?;
+        let (src, dst) = (dir.join("a.conf"), dir.join("b.conf"));
+        for (file, option) in [
+            (format!("src = {dir:?}, dst = {dst:?}, backup = true"), "backup"),
+            (format!("src = {src:?}, dst = {dst:?}, sync = true"), "sync"),
+            (format!("src = {dir:?}, dst = {dst:?}, exclude = ['*.log']"), "exclude"),
+        ] {
+            let error = run_task_type(&format!("file = {{ {file} }}"), &Context::new());
+            match error.unwrap_err().downcast_ref() {
+                Some(Error::FileOptionNotApplicable(name, _)) => assert_eq!(name, option),
+                error => panic!("unexpected error {error:?}"),
+            }
+        }
+        assert!(!dst.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_upload_needed() {
+        let sha256 = format!("{:x}", Sha256::digest(b"Test"));
+        assert!(!RemoteFile::Exists(Some(sha256.to_owned())).upload_needed(&sha256));
+        assert!(RemoteFile::Exists(Some("0".repeat(64))).upload_needed(&sha256));
+        assert!(RemoteFile::Exists(None).upload_needed(&sha256));
+        assert!(RemoteFile::Absent.upload_needed(&sha256));
+        assert!(RemoteFile::Directory.upload_needed(&sha256));
+    }
+
+    #[test]
+    fn file_sync_plan() {
+        let local = BTreeMap::from([
+            ("same".to_string(), "1".to_string()),
+            ("changed".to_string(), "2".to_string()),
+            ("new".to_string(), "3".to_string()),
+        ]);
+        let remote = BTreeMap::from([
+            ("same".to_string(), Some("1".to_string())),
+            ("changed".to_string(), Some("0".to_string())),
+            ("old".to_string(), Some("4".to_string())),
+            ("kept.log".to_string(), Some("5".to_string())),
+        ]);
+        let excluded = |name: &str| name.ends_with(".log");
+        let (upload, remove) = sync_plan(&local, &remote, excluded);
+        assert_eq!(upload, ["changed", "new"]);
+        assert_eq!(remove, ["old"]);
+
+        let unknown = remote.keys().map(|n| (n.to_owned(), None)).collect();
+        let (upload, remove) = sync_plan(&local, &unknown, excluded);
+        assert_eq!(upload, ["changed", "new", "same"]);
+        assert_eq!(remove, ["old"]);
+
+        let synced = local.iter().map(|(n, h)| (n.to_owned(), Some(h.to_owned()))).collect();
+        let (upload, remove) = sync_plan(&local, &synced, excluded);
+        assert!(upload.is_empty());
+        assert!(remove.is_empty());
+    }
+
+    #[test]
+    fn file_attrs_differ_tests() -> Result<()> {
+        assert_eq!(attrs_differ_tests(None, None)?, Some("".to_string()));
+        assert_eq!(
+            attrs_differ_tests(Some("root:adm"), Some("0640"))?,
+            Some("! -user root -o ! -group adm -o ! -perm 0640".to_string())
+        );
+        assert_eq!(attrs_differ_tests(Some(":adm"), None)?, Some("! -group adm".to_string()));
+        assert_eq!(attrs_differ_tests(Some("1000"), None)?, Some("! -user 1000".to_string()));
+        assert_eq!(attrs_differ_tests(Some("user:"), None)?, None);
+        assert_eq!(attrs_differ_tests(None, Some("u+x"))?, None);
+        assert_eq!(attrs_differ_tests(None, Some("0648"))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_format_from_path() -> Result<()> {
+        let format = |path: &str| ArchiveFormat::from_path(Path::new(path));
+        assert!(matches!(format("/tmp/log.tar.gz")?, ArchiveFormat::TarGz));
+        assert!(matches!(format("log.tgz")?, ArchiveFormat::TarGz));
+        assert!(matches!(format("log.tar.zst")?, ArchiveFormat::TarZst));
+        assert!(matches!(format("log.tar")?, ArchiveFormat::Tar));
+        assert!(matches!(format("log.backup")?, ArchiveFormat::Tar));
+        assert!(matches!(format("log")?, ArchiveFormat::Tar));
+
+        let error = format("log.zip").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::ArchiveFormatUnsupported(_))));
+        assert!(format("log.tar.xz").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_glob_cmd() -> Result<()> {
+        let cmd = |pattern: &str| glob_cmd(Path::new(pattern));
+        assert_eq!(
+            cmd("/var/log/*.log")?,
+            r#"for f in /var/log/*.log; do [ -e "$f" ] && printf '%s\n' "$f"; done; true"#
+        );
+        assert!(
+            cmd("/my logs/app*/current.log")?.contains("for f in '/my logs'/app*/current.log;")
+        );
+        assert!(cmd("/*.log")?.contains("for f in /*.log;"));
+        assert!(cmd("*.log")?.contains("for f in *.log;"));
+        assert!(cmd("/var/log/syslog").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_per_worker_by_default() -> Result<()> {
+        let (src_dir, dir) = (mktemp_dir()?, mktemp_dir()?);
+        fs::write(src_dir.join("x.log"), "x")?;
+        let get = toml::from_str::<GetType>(&format!("src = {:?}", src_dir.join("x.log")))?;
+        let worker = Worker::test_host();
+        let (storages, tasklines) = (Default::default(), Default::default());
+        let mut env = Env {
+            dir: &dir,
+            storages: &storages,
+            tasklines: &tasklines,
+            workers: &vec![],
+            task_workers: 1,
+        };
+
+        let dst = dir.join("x.log");
+        assert_eq!(get.run(&Context::new(), &env, &worker)?, dst.to_string_lossy().as_ref());
+
+        env.task_workers = 2;
+        let dst = dir.join("host/x.log");
+        assert_eq!(get.run(&Context::new(), &env, &worker)?, serde_json::json!([dst]));
+        assert_eq!(fs::read_to_string(dst)?, "x");
+
+        let toml = format!("src = {:?}\ndst = {:?}", src_dir.join("x.log"), dir.join("y.log"));
+        let get = toml::from_str::<GetType>(&format!("{toml}\ndst-per-worker = false"))?;
+        let dst = dir.join("y.log");
+        assert_eq!(get.run(&Context::new(), &env, &worker)?, dst.to_string_lossy().as_ref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_dst() -> Result<()> {
+        let manifest_dir = Path::new("/manifest");
+        let single = |src: &str, dst: Option<&str>| {
+            GetType::single_dst(Path::new(src), dst.map(PathBuf::from), manifest_dir)
+        };
+        assert_eq!(single("/etc/os-release", None)?, Path::new("/manifest/os-release"));
+        assert_eq!(single("/etc/os-release", Some("os"))?, Path::new("os"));
+        assert!(single("/", None).is_err());
+
+        let dir = |dst: Option<&str>, worker: Option<&str>| {
+            GetType::dst_dir(dst.map(PathBuf::from), manifest_dir, worker)
+        };
+        assert_eq!(dir(None, None), Path::new("/manifest"));
+        assert_eq!(dir(Some("out"), None), Path::new("/manifest/out"));
+        assert_eq!(dir(None, Some("w1")), Path::new("/manifest/w1"));
+        assert_eq!(dir(Some("out"), Some("w1")), Path::new("/manifest/out/w1"));
+        assert_eq!(dir(Some("/out"), Some("w1")), Path::new("/out/w1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_register() -> Result<()> {
+        let toml = "shell = { cmd = 'echo out; echo err >&2; exit 3', check = false }";
+        let result = run_task_type(toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&serde_json::json!(["out"])));
+        let register = result.register_value(Duration::from_secs(2)).expect("register is set");
+        assert_eq!(
+            register,
+            serde_json::json!({
+                "stdout": "out\n",
+                "stderr": "err\n",
+                "rc": 3,
+                "matched": false,
+                "changed": true,
+                "duration": 2.0,
+            })
+        );
+
+        let toml = "exec = { args = ['echo', 'out'] }";
+        let register = run_task_type(toml, &Context::new())?.register_value(Duration::ZERO);
+        assert_eq!(register.unwrap()["stdout"], "out\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_register() -> Result<()> {
+        let toml = "test = { commands = ['echo a', ['false']], check = false }";
+        let result = run_task_type(toml, &Context::new())?;
+        assert_eq!(result.as_value(), Some(&Value::Bool(false)));
+        let register = result.register_value(Duration::ZERO).expect("register is set");
+        assert_eq!(register["success"], false);
+        assert_eq!(register["commands"][0]["stdout"], "a\n");
+        assert_eq!(register["commands"][1]["rc"], 1);
+        assert_eq!(register["commands"][1]["changed"], true);
+
+        Ok(())
+    }
 }
@@ -1,22 +1,37 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub use regex::Regex;
 pub use tera::Context;
 
 use anyhow::Context as AnyhowContext;
 use anyhow::{bail, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{Local, Utc};
 use cmd_lib::run_fun;
 use inquire::{Confirm, Text};
 use lazy_static::lazy_static;
+use md5::Md5;
+use rand::Rng;
 use serde_json::value::{to_value, Value};
 use serde_json::{to_string, to_string_pretty};
+use sha2::{Digest, Sha256};
 use tera::Tera;
+use uuid::Uuid;
 
 use crate::cmd::Cmd;
 use crate::error::Error;
+use crate::files::walk_files;
 use crate::fs_var::FsVar;
+use crate::items::glob_paths;
+use crate::task_type::CmdParams;
 use crate::tmpdir::TMPDIR;
+use crate::worker::Worker;
 
 fn wrap_error(error: anyhow::Error) -> tera::Error {
     tera::Error::msg(error)
@@ -52,6 +67,53 @@ fn basename(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value
     }
 }
 
+fn scalar_string(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => bail!(Error::WrongValueType),
+    }
+}
+
+fn string_arg(args: &HashMap<String, Value>, name: &str, default: Option<&str>) -> Result<String> {
+    match (args.get(name), default) {
+        (Some(Value::String(s)), _) => Ok(s.to_string()),
+        (Some(Value::Number(n)), _) => Ok(n.to_string()),
+        (Some(_), _) => bail!(Error::WrongArgumentType(name.to_string())),
+        (None, Some(default)) => Ok(default.to_string()),
+        (None, None) => bail!(Error::NoArgument(name.to_string())),
+    }
+}
+
+fn bool_arg(args: &HashMap<String, Value>, name: &str, default: bool) -> Result<bool> {
+    match args.get(name) {
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => bail!(Error::WrongArgumentType(name.to_string())),
+        None => Ok(default),
+    }
+}
+
+fn int_arg(args: &HashMap<String, Value>, name: &str, default: Option<i64>) -> Result<i64> {
+    match (args.get(name), default) {
+        (Some(Value::Number(n)), _) => {
+            n.as_i64().ok_or_else(|| Error::WrongArgumentType(name.to_string()).into())
+        }
+        (Some(_), _) => bail!(Error::WrongArgumentType(name.to_string())),
+        (None, Some(default)) => Ok(default),
+        (None, None) => bail!(Error::NoArgument(name.to_string())),
+    }
+}
+
+fn b64decode(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let bytes = BASE64_STANDARD.decode(scalar_string(value)?.trim())?;
+    Ok(Value::String(String::from_utf8(bytes)?))
+}
+
+fn b64encode(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(BASE64_STANDARD.encode(scalar_string(value)?)))
+}
+
 fn cond(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
     let error_not_support = "Value of not supported type";
     match value {
@@ -65,6 +127,20 @@ fn cond(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
     }
 }
 
+fn dict2items(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let key_name = string_arg(args, "key_name", Some("key"))?;
+    let value_name = string_arg(args, "value_name", Some("value"))?;
+    let Value::Object(object) = value else {
+        bail!(Error::WrongValueType);
+    };
+
+    let items = object
+        .iter()
+        .map(|(k, v)| serde_json::json!({ &key_name: k, &value_name: v }))
+        .collect::<Vec<_>>();
+    Ok(Value::Array(items))
+}
+
 fn dirname(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
     let error_not_support = "Value of not supported type";
     match value {
@@ -80,6 +156,18 @@ fn dirname(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value>
     }
 }
 
+fn from_json(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(serde_json::from_str(&scalar_string(value)?)?)
+}
+
+fn from_toml(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(toml::from_str(&scalar_string(value)?)?)
+}
+
+fn from_yaml(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(serde_yaml::from_str(&scalar_string(value)?)?)
+}
+
 fn fs_helper(name: &str) -> Result<Value> {
     let fs_var = FsVar::new(name)?;
     if !fs_var.exists() {
@@ -107,6 +195,22 @@ fn is_empty(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
     }
 }
 
+fn items2dict(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let key_name = string_arg(args, "key_name", Some("key"))?;
+    let value_name = string_arg(args, "value_name", Some("value"))?;
+    let Value::Array(items) = value else {
+        bail!(Error::WrongValueType);
+    };
+
+    let mut object = serde_json::Map::new();
+    for item in items {
+        let key = item.get(&key_name).ok_or(Error::WrongValueType)?;
+        let value = item.get(&value_name).cloned().unwrap_or(Value::Null);
+        object.insert(scalar_string(key)?, value);
+    }
+    Ok(Value::Object(object))
+}
+
 pub fn json_encode(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
     let pretty = args.get("pretty").and_then(Value::as_bool).unwrap_or(false);
 
@@ -127,6 +231,62 @@ fn lines(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
     }
 }
 
+fn md5(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(format!("{:x}", Md5::digest(scalar_string(value)?))))
+}
+
+fn merge_values(base: &mut Value, other: &Value, deep: bool) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(key) {
+                    Some(old) if deep && old.is_object() && value.is_object() => {
+                        merge_values(old, value, deep)
+                    }
+                    _ => {
+                        base.insert(key.to_string(), value.to_owned());
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other.to_owned(),
+    }
+}
+
+fn merge(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let with = args.get("with").ok_or_else(|| Error::NoArgument("with".to_string()))?;
+    if !with.is_object() {
+        bail!(Error::WrongArgumentType("with".to_string()));
+    }
+    let deep = match args.get("deep") {
+        Some(Value::Bool(b)) => *b,
+        Some(_) => bail!(Error::WrongArgumentType("deep".to_string())),
+        None => true,
+    };
+    if !value.is_object() {
+        bail!(Error::WrongValueType);
+    }
+
+    let mut merged = value.to_owned();
+    merge_values(&mut merged, with, deep);
+    Ok(merged)
+}
+
+fn path_join(dir: &Path, value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let mut path = dir.join(scalar_string(value)?);
+    match args.get("path") {
+        Some(Value::Array(parts)) => {
+            for part in parts {
+                path.push(scalar_string(part)?);
+            }
+        }
+        Some(_) => path.push(string_arg(args, "path", None)?),
+        None => bail!(Error::NoArgument("path".to_string())),
+    }
+
+    Ok(Value::String(path.to_string_lossy().to_string()))
+}
+
 fn quote_string(value: &Value) -> tera::Result<String> {
     let error_not_support = "Value of not supported type";
     let s = match value {
@@ -297,6 +457,137 @@ fn re_sub(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
     }
 }
 
+fn sha256(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(format!("{:x}", Sha256::digest(scalar_string(value)?))))
+}
+
+fn shell_split(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let command = scalar_string(value)?;
+    let args = shlex::split(&command).ok_or(Error::WrongValueType)?;
+    Ok(Value::Array(args.into_iter().map(Value::String).collect()))
+}
+
+fn to_csv(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let Value::Array(rows) = value else {
+        bail!(Error::WrongValueType);
+    };
+
+    let mut header: Vec<String> = vec![];
+    for row in rows {
+        if let Value::Object(row) = row {
+            for key in row.keys() {
+                if !header.contains(key) {
+                    header.push(key.to_string());
+                }
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    if !header.is_empty() {
+        writer.write_record(&header)?;
+    }
+    for row in rows {
+        let record = match row {
+            Value::Object(row) => header
+                .iter()
+                .map(|key| row.get(key).map(scalar_string).unwrap_or(Ok("".to_string())))
+                .collect::<Result<Vec<_>>>()?,
+            Value::Array(row) => row.iter().map(scalar_string).collect::<Result<Vec<_>>>()?,
+            _ => bail!(Error::WrongValueType),
+        };
+        writer.write_record(&record)?;
+    }
+
+    Ok(Value::String(String::from_utf8(writer.into_inner()?)?))
+}
+
+fn to_toml(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(toml::to_string(value)?))
+}
+
+fn to_yaml(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(serde_yaml::to_string(value)?))
+}
+
+fn uuid5(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let namespace = match args.get("namespace") {
+        None => Uuid::NAMESPACE_DNS,
+        Some(Value::String(namespace)) => match namespace.as_str() {
+            "dns" => Uuid::NAMESPACE_DNS,
+            "oid" => Uuid::NAMESPACE_OID,
+            "url" => Uuid::NAMESPACE_URL,
+            "x500" => Uuid::NAMESPACE_X500,
+            namespace => Uuid::parse_str(namespace)
+                .map_err(|_| Error::WrongArgumentType("namespace".to_string()))?,
+        },
+        Some(_) => bail!(Error::WrongArgumentType("namespace".to_string())),
+    };
+
+    Ok(Value::String(Uuid::new_v5(&namespace, scalar_string(value)?.as_bytes()).to_string()))
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum VersionPart {
+    Alpha(String),
+    Number(u64),
+}
+
+fn version_parts(version: &str) -> Vec<VersionPart> {
+    lazy_static! {
+        static ref VERSION_PART: Regex = Regex::new(r"\d+|[[:alpha:]]+").unwrap();
+    }
+
+    VERSION_PART
+        .find_iter(version)
+        .map(|m| match m.as_str().parse() {
+            Ok(n) => VersionPart::Number(n),
+            Err(_) => VersionPart::Alpha(m.as_str().to_string()),
+        })
+        .collect()
+}
+
+fn version_cmp(value: &Value, args: &HashMap<String, Value>, arg: &str) -> Result<Ordering> {
+    let version = version_parts(&scalar_string(value)?);
+    let other = version_parts(&string_arg(args, arg, None)?);
+    Ok(version.cmp(&other))
+}
+
+fn version_eq(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(version_cmp(value, args, "to")?.is_eq()))
+}
+
+fn version_ge(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(version_cmp(value, args, "than")?.is_ge()))
+}
+
+fn version_gt(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(version_cmp(value, args, "than")?.is_gt()))
+}
+
+fn version_le(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(version_cmp(value, args, "than")?.is_le()))
+}
+
+fn version_lt(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::Bool(version_cmp(value, args, "than")?.is_lt()))
+}
+
+fn zip(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let Value::Array(a) = value else {
+        bail!(Error::WrongValueType);
+    };
+    let Some(Value::Array(b)) = args.get("with") else {
+        match args.get("with") {
+            Some(_) => bail!(Error::WrongArgumentType("with".to_string())),
+            None => bail!(Error::NoArgument("with".to_string())),
+        }
+    };
+
+    let pairs = a.iter().zip(b).map(|(a, b)| Value::Array(vec![a.to_owned(), b.to_owned()]));
+    Ok(Value::Array(pairs.collect()))
+}
+
 fn confirm(args: &HashMap<String, Value>) -> tera::Result<Value> {
     let msg = match args.get("msg") {
         Some(val) => match tera::from_value::<String>(val.to_owned()) {
@@ -447,46 +738,319 @@ fn host_cmd(args: &HashMap<String, Value>) -> tera::Result<Value> {
     }
 }
 
+fn file_exists(dir: &Path, args: &HashMap<String, Value>) -> Result<Value> {
+    let path = dir.join(string_arg(args, "path", None)?);
+    Ok(Value::Bool(path.exists()))
+}
+
+fn glob(dir: &Path, args: &HashMap<String, Value>) -> Result<Value> {
+    let paths = glob_paths(dir, &string_arg(args, "pattern", None)?)?;
+    Ok(Value::Array(paths.into_iter().map(Value::String).collect()))
+}
+
+fn now(args: &HashMap<String, Value>) -> Result<Value> {
+    let utc = bool_arg(args, "utc", false)?;
+    if bool_arg(args, "timestamp", false)? {
+        return Ok(Value::from(Utc::now().timestamp()));
+    }
+
+    let now = if utc { Utc::now().fixed_offset() } else { Local::now().fixed_offset() };
+    let now = match args.get("format") {
+        Some(_) => {
+            let format = string_arg(args, "format", None)?;
+            let mut now_str = String::new();
+            write!(now_str, "{}", now.format(&format))
+                .map_err(|_| Error::WrongArgumentType("format".to_string()))?;
+            now_str
+        }
+        None => now.to_rfc3339(),
+    };
+
+    Ok(Value::String(now))
+}
+
+fn random(args: &HashMap<String, Value>) -> Result<Value> {
+    let min = int_arg(args, "min", Some(0))?;
+    let max = int_arg(args, "max", None)?;
+    if max <= min {
+        bail!(Error::WrongArgumentType("max".to_string()));
+    }
+
+    Ok(Value::from(rand::thread_rng().gen_range(min..max)))
+}
+
+fn random_string(args: &HashMap<String, Value>) -> Result<Value> {
+    let len = int_arg(args, "len", Some(16))?;
+    let len = usize::try_from(len).map_err(|_| Error::WrongArgumentType("len".to_string()))?;
+    let chars = match args.get("chars") {
+        Some(_) => string_arg(args, "chars", None)?.chars().collect::<Vec<_>>(),
+        None => ('a'..='z').chain('A'..='Z').chain('0'..='9').collect(),
+    };
+    if chars.is_empty() {
+        bail!(Error::WrongArgumentType("chars".to_string()));
+    }
+
+    let mut rng = rand::thread_rng();
+    let string = (0..len).map(|_| chars[rng.gen_range(0..chars.len())]).collect();
+    Ok(Value::String(string))
+}
+
+fn read_file(dir: &Path, args: &HashMap<String, Value>) -> Result<Value> {
+    let path = dir.join(string_arg(args, "path", None)?);
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read file `{}`", path.display()))?;
+    if bool_arg(args, "lines", false)? {
+        lines(&Value::String(content), &HashMap::new())
+    } else {
+        Ok(Value::String(content))
+    }
+}
+
+fn uuid(_args: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(Uuid::new_v4().to_string()))
+}
+
+thread_local! {
+    static WORKER: RefCell<Option<Arc<Worker>>> = const { RefCell::new(None) };
+}
+
+/// Keeps the worker bound for `worker_cmd` function in templates rendered by
+/// the current thread, restores the previous binding on drop.
+pub struct WorkerBinding {
+    previous: Option<Arc<Worker>>,
+}
+
+impl Drop for WorkerBinding {
+    fn drop(&mut self) {
+        WORKER.with(|w| *w.borrow_mut() = self.previous.take());
+    }
+}
+
+pub fn bind_worker(worker: &Arc<Worker>) -> WorkerBinding {
+    let previous = WORKER.with(|w| w.borrow_mut().replace(Arc::clone(worker)));
+    WorkerBinding { previous }
+}
+
+/// Unbinds the worker, e.g. while nested manifests with their own workers are
+/// loaded and run.
+pub fn unbind_worker() -> WorkerBinding {
+    let previous = WORKER.with(|w| w.borrow_mut().take());
+    WorkerBinding { previous }
+}
+
+fn worker_cmd(args: &HashMap<String, Value>) -> Result<Value> {
+    let worker = WORKER.with(|w| w.borrow().to_owned());
+    let worker = worker.ok_or(Error::WorkerCmdNoWorker)?;
+
+    let check = bool_arg(args, "check", true)?;
+    let capture_stdout = match string_arg(args, "capture", Some("stdout"))?.as_str() {
+        "stdout" => true,
+        "stderr" => false,
+        _ => bail!(Error::WrongArgumentType("capture".to_string())),
+    };
+
+    let mut params = CmdParams::quiet();
+    params.check = Some(false);
+    let (command, out) = match args.get("cmd") {
+        Some(Value::String(cmd)) => (cmd.to_string(), worker.shell(cmd, &params)?),
+        Some(Value::Array(a)) => {
+            let cmd = a.iter().map(scalar_string).collect::<Result<Vec<_>>>()?;
+            (cmd.join(" "), worker.exec(&cmd, &params)?)
+        }
+        Some(_) => bail!(Error::WrongArgumentType("cmd".to_string())),
+        None => bail!(Error::NoArgument("cmd".to_string())),
+    };
+
+    if check && !out.success() {
+        bail!(Error::CommandFailedExitCode(command));
+    }
+
+    if capture_stdout {
+        Ok(Value::String(out.stdout().trim_end().to_string()))
+    } else {
+        Ok(Value::String(out.stderr().trim_end().to_string()))
+    }
+}
+
 fn tmpdir(_args: &HashMap<String, Value>) -> tera::Result<Value> {
     Ok(Value::String(TMPDIR.display().to_string()))
 }
 
+lazy_static! {
+    static ref RENDERER: Tera = {
+        let mut tera = Tera::default();
+
+        tera.register_filter("b64decode", wrap_filter(Box::new(b64decode)));
+        tera.register_filter("b64encode", wrap_filter(Box::new(b64encode)));
+        tera.register_filter("basename", basename);
+        tera.register_filter("cond", cond);
+        tera.register_filter("dict2items", wrap_filter(Box::new(dict2items)));
+        tera.register_filter("dirname", dirname);
+        tera.register_filter("from_json", wrap_filter(Box::new(from_json)));
+        tera.register_filter("from_toml", wrap_filter(Box::new(from_toml)));
+        tera.register_filter("from_yaml", wrap_filter(Box::new(from_yaml)));
+        tera.register_filter("fs", wrap_filter(Box::new(fs_filter)));
+        tera.register_filter("is_empty", is_empty);
+        tera.register_filter("items2dict", wrap_filter(Box::new(items2dict)));
+        tera.register_filter("j", json_encode);
+        tera.register_filter("json", json_encode);
+        tera.register_filter("lines", wrap_filter(Box::new(lines)));
+        tera.register_filter("md5", wrap_filter(Box::new(md5)));
+        tera.register_filter("merge", wrap_filter(Box::new(merge)));
+        tera.register_filter("q", wrap_filter(Box::new(quote)));
+        tera.register_filter("quote", wrap_filter(Box::new(quote)));
+        tera.register_filter("re_match", wrap_filter(Box::new(re_match)));
+        tera.register_filter("re_sub", wrap_filter(Box::new(re_sub)));
+        tera.register_filter("sha256", wrap_filter(Box::new(sha256)));
+        tera.register_filter("shell_split", wrap_filter(Box::new(shell_split)));
+        tera.register_filter("to_csv", wrap_filter(Box::new(to_csv)));
+        tera.register_filter("to_toml", wrap_filter(Box::new(to_toml)));
+        tera.register_filter("to_yaml", wrap_filter(Box::new(to_yaml)));
+        tera.register_filter("uuid5", wrap_filter(Box::new(uuid5)));
+        tera.register_filter("version_eq", wrap_filter(Box::new(version_eq)));
+        tera.register_filter("version_ge", wrap_filter(Box::new(version_ge)));
+        tera.register_filter("version_gt", wrap_filter(Box::new(version_gt)));
+        tera.register_filter("version_le", wrap_filter(Box::new(version_le)));
+        tera.register_filter("version_lt", wrap_filter(Box::new(version_lt)));
+        tera.register_filter("zip", wrap_filter(Box::new(zip)));
+
+        tera.register_function("confirm", confirm);
+        tera.register_function("fs", wrap_function(Box::new(fs_function)));
+        tera.register_function("input", wrap_function(Box::new(input)));
+        tera.register_function("host_cmd", host_cmd);
+        tera.register_function("now", wrap_function(Box::new(now)));
+        tera.register_function("random", wrap_function(Box::new(random)));
+        tera.register_function("random_string", wrap_function(Box::new(random_string)));
+        tera.register_function("tmpdir", tmpdir);
+        tera.register_function("uuid", wrap_function(Box::new(uuid)));
+        tera.register_function("worker_cmd", wrap_function(Box::new(worker_cmd)));
+        tera
+    };
+}
+
+/// Register functions which depend on the rendering context.
+fn register_context_functions(tera: &mut Tera, context: &Context) {
+    let dir =
+        PathBuf::from(context.get("manifest_dir").and_then(|d| d.as_str()).unwrap_or_default());
+
+    let file_dir = dir.to_owned();
+    let f = move |args: &HashMap<String, Value>| file_exists(&file_dir, args);
+    tera.register_function("file_exists", wrap_function(Box::new(f)));
+    let glob_dir = dir.to_owned();
+    let f = move |args: &HashMap<String, Value>| glob(&glob_dir, args);
+    tera.register_function("glob", wrap_function(Box::new(f)));
+    let path_dir = dir.to_owned();
+    let f = move |value: &Value, args: &HashMap<String, Value>| path_join(&path_dir, value, args);
+    tera.register_filter("path_join", wrap_filter(Box::new(f)));
+    let f = move |args: &HashMap<String, Value>| read_file(&dir, args);
+    tera.register_function("read_file", wrap_function(Box::new(f)));
+}
+
 pub fn render<S: ToString, P: AsRef<str>>(
     context: &Context,
     template: S,
     place: P,
 ) -> Result<String> {
-    lazy_static! {
-        pub static ref RENDERER: Tera = {
-            let mut tera = Tera::default();
-
-            tera.register_filter("basename", basename);
-            tera.register_filter("cond", cond);
-            tera.register_filter("dirname", dirname);
-            tera.register_filter("fs", wrap_filter(Box::new(fs_filter)));
-            tera.register_filter("is_empty", is_empty);
-            tera.register_filter("j", json_encode);
-            tera.register_filter("json", json_encode);
-            tera.register_filter("lines", wrap_filter(Box::new(lines)));
-            tera.register_filter("q", wrap_filter(Box::new(quote)));
-            tera.register_filter("quote", wrap_filter(Box::new(quote)));
-            tera.register_filter("re_match", wrap_filter(Box::new(re_match)));
-            tera.register_filter("re_sub", wrap_filter(Box::new(re_sub)));
-
-            tera.register_function("confirm", confirm);
-            tera.register_function("fs", wrap_function(Box::new(fs_function)));
-            tera.register_function("input", wrap_function(Box::new(input)));
-            tera.register_function("host_cmd", host_cmd);
-            tera.register_function("tmpdir", tmpdir);
-            tera
-        };
-    }
-    RENDERER
-        .to_owned()
-        .render_str(&template.to_string(), context)
+    let mut tera = RENDERER.to_owned();
+    register_context_functions(&mut tera, context);
+    tera.render_str(&template.to_string(), context)
         .with_context(|| format!("Failed to render template in {}", place.as_ref()))
 }
 
+fn template_name(path: &Path, dir: &Path) -> String {
+    let name = path.strip_prefix(dir).unwrap_or(path);
+    name.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Renders template files with the same filters and functions as `render`.
+/// Templates loaded together could `include`, `import` or `extends` each other
+/// by names relative to the directory they are loaded from.
+pub struct FileRenderer {
+    tera: Tera,
+    dir: PathBuf,
+}
+
+impl FileRenderer {
+    fn new(dir: &Path, files: &[PathBuf]) -> Result<Self> {
+        let mut tera = RENDERER.to_owned();
+        tera.autoescape_on(vec![]);
+        let mut templates = Vec::with_capacity(files.len());
+        for file in files {
+            let content = fs::read_to_string(file)
+                .with_context(|| format!("Failed to read template `{}`", file.display()))?;
+            templates.push((template_name(file, dir), content));
+        }
+        tera.add_raw_templates(templates)
+            .with_context(|| format!("Failed to load templates from `{}`", dir.display()))?;
+
+        Ok(Self { tera, dir: dir.to_owned() })
+    }
+
+    /// Load a template file and templates it references by `include`, `import`
+    /// or `extends`, which are resolved relative to the file's directory.
+    pub fn from_file(file: &Path) -> Result<Self> {
+        lazy_static! {
+            static ref REFERENCE: Regex =
+                Regex::new(r#"\{%-?\s*(?:include|import|extends)\s+(\[[^\]]*\]|"[^"]*"|'[^']*')"#)
+                    .unwrap();
+            static ref QUOTED: Regex = Regex::new(r#""([^"]*)"|'([^']*)'"#).unwrap();
+        }
+
+        let dir = file.parent().unwrap_or(Path::new(""));
+        let mut files = vec![];
+        let mut pending = vec![file.to_owned()];
+        while let Some(path) = pending.pop() {
+            if files.contains(&path) {
+                continue;
+            }
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template `{}`", path.display()))?;
+            for reference in REFERENCE.captures_iter(&content) {
+                for name in QUOTED.captures_iter(&reference[1]) {
+                    let name = name.get(1).or(name.get(2)).map_or("", |m| m.as_str());
+                    let path = dir.join(name);
+                    // missing templates are reported by tera or skipped by `ignore missing`
+                    if path.is_file() {
+                        pending.push(path);
+                    }
+                }
+            }
+            files.push(path);
+        }
+
+        Self::new(dir, &files)
+    }
+
+    /// Load all files from the directory tree as templates.
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        Self::new(dir, &walk_files(dir)?)
+    }
+
+    /// Names of the loaded templates, they are paths relative to the directory.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.tera.get_template_names().map(|n| n.to_string()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn render<S: AsRef<Path>, P: AsRef<str>>(
+        &self,
+        context: &Context,
+        file: S,
+        place: P,
+    ) -> Result<String> {
+        let name = template_name(file.as_ref(), &self.dir);
+        let mut tera = self.tera.to_owned();
+        register_context_functions(&mut tera, context);
+        tera.render(&name, context).with_context(|| {
+            format!("Failed to render template file `{}` in {}", name, place.as_ref())
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -607,7 +1171,7 @@ mod tests {
     fn filter_quote_in_quotation_marks() -> Result<()> {
         let map = HashMap::new();
         for s in ["$HOME", "one two", r#"q"q"#, "`date`", "echo 1 | cat"] {
-            assert_eq!(quote(&to_value(&s)?, &map)?, to_value(format!("'{}'", s))?);
+            assert_eq!(quote(&to_value(s)?, &map)?, to_value(format!("'{}'", s))?);
         }
 
         Ok(())
@@ -807,4 +1371,289 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn file_renderer_include_sibling() -> Result<()> {
+        let dir = crate::tmpdir::mktemp_dir()?;
+        fs::create_dir(dir.join("parts"))?;
+        fs::write(dir.join("main.j2"), "{{ name }} {% include 'parts/footer.html' %}")?;
+        fs::write(dir.join("parts/footer.html"), "{% include ['missing.j2', 'sign.txt'] %}")?;
+        fs::write(dir.join("sign.txt"), "footer")?;
+        fs::write(dir.join("other.j2"), "{% broken")?;
+        let mut context = Context::new();
+        context.insert("name", "main");
+
+        let renderer = FileRenderer::from_file(&dir.join("main.j2"))?;
+        assert_eq!(renderer.render(&context, dir.join("main.j2"), "test")?, "main footer");
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_from_json() -> Result<()> {
+        let value = from_json(&to_value(r#"{"a": [1, "b"]}"#)?, &HashMap::new())?;
+        assert_eq!(value, serde_json::json!({"a": [1, "b"]}));
+        assert!(from_json(&to_value("{")?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_yaml() -> Result<()> {
+        let value = serde_json::json!({"name": "lineup", "ports": [80]});
+        let yaml = to_yaml(&value, &HashMap::new())?;
+        assert_eq!(yaml, to_value("name: lineup\nports:\n- 80\n")?);
+        assert_eq!(from_yaml(&yaml, &HashMap::new())?, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_toml() -> Result<()> {
+        let value = serde_json::json!({"name": "lineup", "server": {"port": 80}});
+        let toml = to_toml(&value, &HashMap::new())?;
+        assert_eq!(toml, to_value("name = \"lineup\"\n\n[server]\nport = 80\n")?);
+        assert_eq!(from_toml(&toml, &HashMap::new())?, value);
+        assert!(to_toml(&to_value([1])?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_b64() -> Result<()> {
+        let encoded = b64encode(&to_value("lineup:pass")?, &HashMap::new())?;
+        assert_eq!(encoded, to_value("bGluZXVwOnBhc3M=")?);
+        assert_eq!(b64decode(&encoded, &HashMap::new())?, to_value("lineup:pass")?);
+        assert!(b64decode(&to_value("!!")?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_hashes() -> Result<()> {
+        assert_eq!(
+            sha256(&to_value("abc")?, &HashMap::new())?,
+            to_value("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")?
+        );
+        assert_eq!(
+            md5(&to_value("abc")?, &HashMap::new())?,
+            to_value("900150983cd24fb0d6963f7d28e17f72")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_uuid5() -> Result<()> {
+        assert_eq!(
+            uuid5(&to_value("python.org")?, &HashMap::new())?,
+            to_value("886313e1-3b8a-5372-9b90-0c9aee199e5d")?
+        );
+        let map = HashMap::from([("namespace".to_string(), to_value("bad")?)]);
+        assert!(uuid5(&to_value("python.org")?, &map).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_to_csv() -> Result<()> {
+        let value = serde_json::json!([{"name": "a", "port": 80}, {"name": "b,c", "up": true}]);
+        assert_eq!(
+            to_csv(&value, &HashMap::new())?,
+            to_value("name,port,up\na,80,\n\"b,c\",,true\n")?
+        );
+        let value = serde_json::json!([["a", 1], ["b", 2]]);
+        assert_eq!(to_csv(&value, &HashMap::new())?, to_value("a,1\nb,2\n")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_merge_deep() -> Result<()> {
+        let value = serde_json::json!({"a": {"b": 1, "c": 2}, "d": [1]});
+        let map =
+            HashMap::from([("with".to_string(), serde_json::json!({"a": {"c": 3}, "d": [2]}))]);
+        assert_eq!(merge(&value, &map)?, serde_json::json!({"a": {"b": 1, "c": 3}, "d": [2]}));
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_merge_shallow() -> Result<()> {
+        let value = serde_json::json!({"a": {"b": 1, "c": 2}});
+        let map = HashMap::from([
+            ("with".to_string(), serde_json::json!({"a": {"c": 3}})),
+            ("deep".to_string(), to_value(false)?),
+        ]);
+        assert_eq!(merge(&value, &map)?, serde_json::json!({"a": {"c": 3}}));
+        let map = HashMap::from([("with".to_string(), to_value([1])?)]);
+        assert!(merge(&value, &map).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_dict2items_items2dict() -> Result<()> {
+        let value = serde_json::json!({"a": 1, "b": "x"});
+        let items = dict2items(&value, &HashMap::new())?;
+        assert_eq!(
+            items,
+            serde_json::json!([{"key": "a", "value": 1}, {"key": "b", "value": "x"}])
+        );
+        assert_eq!(items2dict(&items, &HashMap::new())?, value);
+
+        let map = HashMap::from([("key_name".to_string(), to_value("name")?)]);
+        let items = dict2items(&value, &map)?;
+        assert_eq!(items[0], serde_json::json!({"name": "a", "value": 1}));
+        assert_eq!(items2dict(&items, &map)?, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_zip() -> Result<()> {
+        let map = HashMap::from([("with".to_string(), to_value(["x", "y"])?)]);
+        assert_eq!(zip(&to_value([1, 2, 3])?, &map)?, serde_json::json!([[1, "x"], [2, "y"]]));
+        assert!(zip(&to_value([1])?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_path_join() -> Result<()> {
+        let dir = Path::new("/manifest");
+        let map = HashMap::from([("path".to_string(), to_value("files/app.conf")?)]);
+        let value = path_join(dir, &to_value("/lineup")?, &map)?;
+        assert_eq!(value, to_value("/lineup/files/app.conf")?);
+        let map = HashMap::from([("path".to_string(), to_value(["files", "app.conf"])?)]);
+        let value = path_join(dir, &to_value("/lineup")?, &map)?;
+        assert_eq!(value, to_value("/lineup/files/app.conf")?);
+        let map = HashMap::from([("path".to_string(), to_value("/etc")?)]);
+        assert_eq!(path_join(dir, &to_value("/lineup")?, &map)?, to_value("/etc")?);
+        let map = HashMap::from([("path".to_string(), to_value("app.conf")?)]);
+        let value = path_join(dir, &to_value("files")?, &map)?;
+        assert_eq!(value, to_value("/manifest/files/app.conf")?);
+
+        let mut context = Context::new();
+        context.insert("manifest_dir", "/manifest");
+        let template = "{{ 'files' | path_join(path='app.conf') }}";
+        assert_eq!(render(&context, template, "test")?, "/manifest/files/app.conf");
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_shell_split() -> Result<()> {
+        let value = to_value(r#"echo "a b" 'c d' e\ f"#)?;
+        assert_eq!(
+            shell_split(&value, &HashMap::new())?,
+            to_value(["echo", "a b", "c d", "e f"])?
+        );
+        assert!(shell_split(&to_value("echo 'a")?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_version() -> Result<()> {
+        let than = |v: &str| HashMap::from([("than".to_string(), to_value(v).unwrap())]);
+        assert_eq!(version_gt(&to_value("1.10")?, &than("1.9"))?, to_value(true)?);
+        assert_eq!(version_lt(&to_value("1.10")?, &than("1.10.1"))?, to_value(true)?);
+        assert_eq!(version_ge(&to_value("2.0-alt1")?, &than("2.0-alt1"))?, to_value(true)?);
+        assert_eq!(version_le(&to_value("2.0-alt2")?, &than("2.0-alt1"))?, to_value(false)?);
+        let to = HashMap::from([("to".to_string(), to_value("1.2.0")?)]);
+        assert_eq!(version_eq(&to_value("1.2_0")?, &to)?, to_value(true)?);
+        assert!(version_gt(&to_value("1")?, &HashMap::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_now() -> Result<()> {
+        let map = HashMap::from([
+            ("format".to_string(), to_value("%Y")?),
+            ("utc".to_string(), to_value(true)?),
+        ]);
+        assert_eq!(now(&map)?.as_str().map(|s| s.len()), Some(4));
+        let map = HashMap::from([("format".to_string(), to_value("%Q")?)]);
+        assert!(now(&map).is_err());
+        let map = HashMap::from([("timestamp".to_string(), to_value(true)?)]);
+        assert!(now(&map)?.is_i64());
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_random() -> Result<()> {
+        let map =
+            HashMap::from([("min".to_string(), to_value(5)?), ("max".to_string(), to_value(7)?)]);
+        let value = random(&map)?.as_i64().unwrap_or_default();
+        assert!((5..7).contains(&value));
+        assert!(random(&HashMap::new()).is_err());
+
+        let map = HashMap::from([
+            ("len".to_string(), to_value(8)?),
+            ("chars".to_string(), to_value("ab")?),
+        ]);
+        let value = random_string(&map)?;
+        assert!(value
+            .as_str()
+            .is_some_and(|s| s.len() == 8 && s.chars().all(|c| "ab".contains(c))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_files() -> Result<()> {
+        let dir = crate::tmpdir::mktemp_dir()?;
+        fs::write(dir.join("key.pub"), "ssh-ed25519 AAAA\nsecond\n")?;
+        let mut context = Context::new();
+        context.insert("manifest_dir", &dir.to_string_lossy().to_string());
+
+        let template = "{{ read_file(path='key.pub', lines=true) | first }}";
+        assert_eq!(render(&context, template, "test")?, "ssh-ed25519 AAAA");
+        let template = "{{ file_exists(path='key.pub') }} {{ file_exists(path='key') }}";
+        assert_eq!(render(&context, template, "test")?, "true false");
+        assert_eq!(
+            render(&context, "{{ glob(pattern='*.pub') | json }}", "test")?,
+            r#"["key.pub"]"#
+        );
+        assert!(render(&context, "{{ read_file(path='absent') }}", "test").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_worker_cmd_without_worker() -> Result<()> {
+        let error = render(&Context::new(), "{{ worker_cmd(cmd='true') }}", "test").unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "function `worker_cmd` can be used only in tasks"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_worker_cmd() -> Result<()> {
+        let worker = Arc::new(Worker::test_host());
+        let binding = bind_worker(&worker);
+        let template = "{{ worker_cmd(cmd='echo host') }} {{ worker_cmd(cmd=['echo', 'exec']) }}";
+        assert_eq!(render(&Context::new(), template, "test")?, "host exec");
+        let template = "{{ worker_cmd(cmd='echo err >&2', capture='stderr') }}";
+        assert_eq!(render(&Context::new(), template, "test")?, "err");
+        assert!(render(&Context::new(), "{{ worker_cmd(cmd='false') }}", "test").is_err());
+        let template = "{{ worker_cmd(cmd='false', check=false) }}";
+        assert_eq!(render(&Context::new(), template, "test")?, "");
+
+        {
+            let _unbinding = unbind_worker();
+            assert!(render(&Context::new(), "{{ worker_cmd(cmd='true') }}", "test").is_err());
+        }
+        assert_eq!(render(&Context::new(), "{{ worker_cmd(cmd='echo') }}", "test")?, "");
+        drop(binding);
+        assert!(render(&Context::new(), "{{ worker_cmd(cmd='true') }}", "test").is_err());
+
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use anyhow::Result;
 use cmd_lib::{run_cmd, run_fun};
 use once_cell::sync::Lazy;
 
@@ -9,3 +10,8 @@ pub static TMPDIR: Lazy<PathBuf> = Lazy::new(|| {
     run_cmd! {mkdir $tmpdir/tmpfiles }.expect("can't create tmpdir/tmpfiles");
     tmpdir
 });
+
+pub fn mktemp_dir() -> Result<PathBuf> {
+    let tmpfiles = TMPDIR.join("tmpfiles");
+    Ok(PathBuf::from(run_fun! {mktemp -d -p $tmpfiles}?))
+}
@@ -1,12 +1,17 @@
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Result};
+use serde_json::Value;
 
 use crate::cmd::CmdOut;
+use crate::config::CONFIG;
 use crate::engine::{Engine, ExistsAction};
 use crate::error::Error;
+use crate::facts;
+use crate::items::Items;
 use crate::manifest::DefaultWorker;
 use crate::manifest::Workers as ManifestWorkers;
 use crate::render::Render;
@@ -21,6 +26,8 @@ pub struct Worker {
     workdir: PathBuf,
     engine: Engine,
     setup: bool,
+    gather_facts: bool,
+    vars: Arc<Mutex<Context>>,
 }
 
 impl PartialEq for Worker {
@@ -44,6 +51,8 @@ impl Ord for Worker {
 }
 
 impl Worker {
+    // workers are ordered by name only, shared vars do not affect the order
+    #[allow(clippy::mutable_key_type)]
     pub fn from_manifest_workers(
         manifest_workers: &ManifestWorkers,
         default: &DefaultWorker,
@@ -53,16 +62,21 @@ impl Worker {
         let mut workers = BTreeSet::new();
         let mut context = context.to_owned();
         for (name, worker) in manifest_workers {
-            let items = worker
-                .items
-                .as_ref()
-                .or(default.items.as_ref())
-                .map(|i| i.list(&context))
+            let worker_items = worker.items.as_ref().or(default.items.as_ref());
+            let items = worker_items
+                .map(|i| i.values(&context, None))
                 .transpose()?
-                .unwrap_or_else(|| vec!["".to_string()]);
-            for item in items {
-                context.insert("item", &item);
-                for row in &worker.table_by_item.list(&context)? {
+                .unwrap_or_else(|| vec![Value::String("".to_string())]);
+            for value in items {
+                let item = match worker_items {
+                    Some(worker_items) => worker_items.item_key(&value),
+                    None => Items::key(&value),
+                };
+                match worker_items {
+                    Some(worker_items) => worker_items.insert(&mut context, "item", &value),
+                    None => context.insert("item", &value),
+                }
+                for row in &worker.table_by_item.list(&context, None)? {
                     if let Some(table_item) = row.get("item") {
                         if *table_item == item {
                             context.insert("row_by_item", &row);
@@ -72,7 +86,7 @@ impl Worker {
 
                 let name = name.render(&context, "name in workers in manifest")?;
                 let name_outer = name.to_string();
-                for row in &worker.table_by_name.list(&context)? {
+                for row in &worker.table_by_name.list(&context, None)? {
                     if let Some(table_name) = row.get("name") {
                         let table_name = table_name
                             .render(&context, "name in table_by_name in workers in manifest")?;
@@ -88,12 +102,16 @@ impl Worker {
                     .or(default.engine.as_ref())
                     .ok_or_else(|| Error::NoEngine(name.to_string()))?;
                 let engine = Engine::from_manifest_engine(&context, engine, dir)?;
+                let gather_facts =
+                    worker.gather_facts.or(default.gather_facts).unwrap_or(CONFIG.gather_facts);
                 workers.insert(Worker {
                     name,
                     name_outer,
                     engine,
                     setup: false,
+                    gather_facts,
                     workdir: PathBuf::default(),
+                    vars: Default::default(),
                 });
             }
         }
@@ -105,6 +123,18 @@ impl Worker {
         self.name_outer.to_string()
     }
 
+    pub fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    pub fn vars(&self) -> Context {
+        self.vars.lock().expect("worker vars lock is poisoned").to_owned()
+    }
+
+    pub fn set_vars(&self, context: Context) {
+        self.vars.lock().expect("worker vars lock is poisoned").extend(context);
+    }
+
     pub fn rename<S: AsRef<str>>(&mut self, new_name: S) -> String {
         let old_name = self.name_outer.to_string();
         self.name_outer = new_name.as_ref().to_string();
@@ -124,13 +154,28 @@ impl Worker {
             if !out.success() {
                 bail!(Error::WorkerSetupFailed(self.name.to_string()))
             }
-            self.workdir = PathBuf::from(out.stdout());
+            self.workdir = PathBuf::from(out.stdout().trim_end());
             self.setup = true;
+            if self.gather_facts {
+                self.gather_facts()?;
+            }
         }
 
         Ok(())
     }
 
+    pub fn gather_facts(&self) -> Result<Value> {
+        let mut params = CmdParams::quiet();
+        params.check = Some(false);
+        let out = self.shell(facts::PROBE, &params)?;
+        let facts = facts::parse(&out.stdout());
+        let mut context = Context::new();
+        context.insert("facts", &facts);
+        self.set_vars(context);
+
+        Ok(facts)
+    }
+
     pub fn ensure_remove(&mut self) -> Result<()> {
         self.engine.remove(&self.name)?;
         self.setup = false;
@@ -162,3 +207,22 @@ impl Worker {
         self.engine.special(&self.name, type_, ignore_unsupported)
     }
 }
+
+#[cfg(test)]
+impl Worker {
+    /// Host worker named `host` with the default config.
+    pub fn test_host() -> Self {
+        crate::config::init_default();
+        let engine = crate::manifest::Engine::Host;
+        Worker {
+            name: "host".to_string(),
+            name_outer: "host".to_string(),
+            workdir: std::env::temp_dir().join("lineup"),
+            engine: Engine::from_manifest_engine(&Context::new(), &engine, Path::new(""))
+                .expect("host engine should be created"),
+            setup: true,
+            gather_facts: false,
+            vars: Default::default(),
+        }
+    }
+}